@@ -0,0 +1,557 @@
+// Disk cache of the downloaded tiles. Every tile file under the cache directory is tracked with
+// its size, last display and expiry in an index stored next to the layer subdirectories. Beyond
+// the configured size the least recently displayed tiles are deleted, expired tiles are
+// downloaded again while their stale copy is shown.
+//
+// mapapp purge [--layer <name>] [--bbox <west,south,east,north>] [--zoom <min>[-<max>]]
+//              [configuration flags]
+//
+// deletes the cached tiles of a layer, a region or both, or the whole cache without flags.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{tile_request, ConfigError, MapConfig, ASSETS_DIRECTORY};
+use crate::download::{parse_bounds, parse_zoom_range};
+use crate::projection::bounds_to_tiles;
+use crate::TileKey;
+
+const INDEX_FILE: &str = "index.json";
+// Seconds between two checks of the cache size.
+const EVICTION_INTERVAL: f32 = 10.0;
+const MEBIBYTE: u64 = 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct CacheEntry {
+    pub bytes: u64,
+    // Unix time in seconds the tile was last displayed.
+    pub last_access: u64,
+    // Unix time in seconds after which the tile is downloaded again.
+    pub expires: u64,
+}
+
+#[derive(Resource)]
+pub struct TileCache {
+    // `assets/` joined with the cache directory.
+    directory: PathBuf,
+    max_bytes: u64,
+    default_max_age: u64,
+    // Entries by tile path relative to `assets/`, as the asset server loads them.
+    entries: HashMap<String, CacheEntry>,
+    // Whether the entries changed since the index was saved.
+    dirty: bool,
+}
+
+impl TileCache {
+    // Reads the index and reconciles it with the files on disk: tiles cached by older versions or
+    // copied by hand are added, deleted ones are forgotten.
+    pub fn open(config: &MapConfig) -> Self {
+        let directory = Path::new(ASSETS_DIRECTORY).join(&config.cache_directory);
+        let mut indexed: HashMap<String, CacheEntry> =
+            match std::fs::read(directory.join(INDEX_FILE)) {
+                Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|err| {
+                    warn!("Ignoring the invalid tile cache index: {}", err);
+                    HashMap::new()
+                }),
+                Err(_) => HashMap::new(),
+            };
+
+        let now = now();
+        let mut entries = HashMap::new();
+        let layers = std::fs::read_dir(&directory)
+            .into_iter()
+            .flatten()
+            .flatten();
+        for layer in layers.filter(|entry| entry.path().is_dir()) {
+            let layer_name = layer.file_name().to_string_lossy().into_owned();
+            for file in std::fs::read_dir(layer.path())
+                .into_iter()
+                .flatten()
+                .flatten()
+            {
+                let Ok(metadata) = file.metadata() else {
+                    continue;
+                };
+                if !metadata.is_file() {
+                    continue;
+                }
+                let path = cache_key(
+                    &Path::new(&config.layer_directory(&layer_name)).join(file.file_name()),
+                );
+                let entry = indexed.remove(&path).unwrap_or_else(|| {
+                    let modified = metadata
+                        .modified()
+                        .ok()
+                        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                        .map_or(now, |age| age.as_secs());
+                    CacheEntry {
+                        bytes: 0,
+                        last_access: modified,
+                        expires: modified + config.cache_max_age,
+                    }
+                });
+                entries.insert(
+                    path,
+                    CacheEntry {
+                        bytes: metadata.len(),
+                        ..entry
+                    },
+                );
+            }
+        }
+
+        Self {
+            directory,
+            max_bytes: config.cache_size * MEBIBYTE,
+            default_max_age: config.cache_max_age,
+            entries,
+            dirty: true,
+        }
+    }
+
+    pub fn total_bytes(&self) -> u64 {
+        self.entries.values().map(|entry| entry.bytes).sum()
+    }
+
+    pub fn is_cached(&self, path: &Path) -> bool {
+        self.entries.contains_key(&cache_key(path))
+    }
+
+    pub fn is_expired(&self, path: &Path) -> bool {
+        self.entries
+            .get(&cache_key(path))
+            .is_some_and(|entry| entry.expires <= now())
+    }
+
+    // Adds a freshly written tile, expiring when the server asked or after the default age.
+    pub fn record(&mut self, path: &Path, expires: Option<u64>) {
+        let now = now();
+        let bytes = std::fs::metadata(Path::new(ASSETS_DIRECTORY).join(path))
+            .map_or(0, |metadata| metadata.len());
+        self.entries.insert(
+            cache_key(path),
+            CacheEntry {
+                bytes,
+                last_access: now,
+                expires: expires.unwrap_or(now + self.default_max_age),
+            },
+        );
+        self.dirty = true;
+    }
+
+    // Marks a cached tile as displayed, moving it to the back of the eviction order.
+    pub fn touch(&mut self, path: &Path) {
+        if let Some(entry) = self.entries.get_mut(&cache_key(path)) {
+            entry.last_access = now();
+            self.dirty = true;
+        }
+    }
+
+    // Deletes the least recently displayed tiles until the cache fits its size, returns the
+    // number of deleted tiles.
+    pub fn evict(&mut self) -> usize {
+        let mut total = self.total_bytes();
+        if total <= self.max_bytes {
+            return 0;
+        }
+        let mut entries: Vec<(String, CacheEntry)> = self
+            .entries
+            .iter()
+            .map(|(path, entry)| (path.clone(), *entry))
+            .collect();
+        entries.sort_by_key(|(_, entry)| entry.last_access);
+        let mut evicted = 0;
+        for (path, entry) in entries {
+            if total <= self.max_bytes {
+                break;
+            }
+            // A tile that cannot be deleted still takes its space, the next older one is tried.
+            if self.remove(&path) {
+                total -= entry.bytes;
+                evicted += 1;
+            }
+        }
+        evicted
+    }
+
+    // Deletes the tiles matching the filter, called with the layer name and tile of every file.
+    // Returns the number of deleted tiles and their size in bytes.
+    pub fn purge(&mut self, filter: impl Fn(&str, TileKey) -> bool) -> (usize, u64) {
+        let matching: Vec<(String, u64)> = self
+            .entries
+            .iter()
+            .filter(|(path, _)| {
+                parse_tile_path(path).is_some_and(|(layer, key)| filter(layer, key))
+            })
+            .map(|(path, entry)| (path.clone(), entry.bytes))
+            .collect();
+        let purged: Vec<u64> = matching
+            .into_iter()
+            .filter(|(path, _)| self.remove(path))
+            .map(|(_, bytes)| bytes)
+            .collect();
+        (purged.len(), purged.iter().sum())
+    }
+
+    // Deletes a cached tile that cannot be displayed, so it is downloaded again.
+    pub fn remove_tile(&mut self, path: &Path) {
+        self.remove(&cache_key(path));
+    }
+
+    // Deletes a tile file and its entry, returns whether it is gone. A file already deleted by
+    // hand is only forgotten.
+    fn remove(&mut self, path: &str) -> bool {
+        if let Err(err) = std::fs::remove_file(Path::new(ASSETS_DIRECTORY).join(path)) {
+            if err.kind() != std::io::ErrorKind::NotFound {
+                warn!("Cannot delete the cached tile {}: {}", path, err);
+                return false;
+            }
+        }
+        self.entries.remove(path);
+        self.dirty = true;
+        true
+    }
+
+    pub fn save(&mut self) {
+        if !self.dirty {
+            return;
+        }
+        let result = std::fs::create_dir_all(&self.directory).and_then(|_| {
+            let json = serde_json::to_vec(&self.entries).map_err(std::io::Error::other)?;
+            std::fs::write(self.directory.join(INDEX_FILE), json)
+        });
+        match result {
+            Ok(()) => self.dirty = false,
+            Err(err) => warn!("Cannot save the tile cache index: {}", err),
+        }
+    }
+}
+
+// Index key of a tile path, independent of how the path was joined.
+fn cache_key(path: &Path) -> String {
+    path.iter()
+        .map(|component| component.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+// Layer name and tile of a `<cache>/<layer>/<z>.<x>.<y>.<pixels>.tile.png` path.
+fn parse_tile_path(path: &str) -> Option<(&str, TileKey)> {
+    let mut components = path.rsplit('/');
+    let file_name = components.next()?;
+    let layer = components.next()?;
+    let mut parts = file_name.split('.');
+    let key = TileKey {
+        zoom_level: parts.next()?.parse().ok()?,
+        x: parts.next()?.parse().ok()?,
+        y: parts.next()?.parse().ok()?,
+    };
+    Some((layer, key))
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs())
+}
+
+// Expiry the server asked for with `Cache-Control` or `Expires`, tiles it forbids caching
+// expire immediately.
+pub fn response_expiry(headers: &ehttp::Headers) -> Option<u64> {
+    let now = now();
+    if let Some(cache_control) = headers.get("cache-control") {
+        for directive in cache_control.split(',').map(str::trim) {
+            if directive == "no-store" || directive == "no-cache" {
+                return Some(now);
+            }
+            if let Some(max_age) = directive.strip_prefix("max-age=") {
+                if let Ok(max_age) = max_age.trim_matches('"').parse::<u64>() {
+                    return Some(now + max_age);
+                }
+            }
+        }
+    }
+    headers.get("expires").map(|expires| {
+        // Invalid dates, like the common "0", mean already expired.
+        parse_http_date(expires).unwrap_or(now)
+    })
+}
+
+// Unix time of an HTTP date, "Sun, 06 Nov 1994 08:49:37 GMT".
+fn parse_http_date(date: &str) -> Option<u64> {
+    let [_, day, month, year, time, "GMT"] = date.split_whitespace().collect::<Vec<_>>()[..] else {
+        return None;
+    };
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let month = MONTHS.iter().position(|name| *name == month)? as i64 + 1;
+    let day: i64 = day.parse().ok()?;
+    let year: i64 = year.parse().ok()?;
+    let mut time = time.split(':').map(|part| part.parse::<i64>().ok());
+    let (hours, minutes, seconds) = (time.next()??, time.next()??, time.next()??);
+
+    // Days since 1970-01-01 in the proleptic Gregorian calendar, with years starting in March.
+    let (year, month) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * month + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+    u64::try_from(days * 86_400 + hours * 3600 + minutes * 60 + seconds).ok()
+}
+
+// Writes a downloaded tile into the cache, returns the expiry the server asked for.
+pub fn store_response(file: &Path, response: &ehttp::Response) -> Result<Option<u64>, String> {
+    if let Some(directory) = file.parent() {
+        std::fs::create_dir_all(directory).map_err(|err| err.to_string())?;
+    }
+    std::fs::write(file, &response.bytes).map_err(|err| err.to_string())?;
+    Ok(response_expiry(&response.headers))
+}
+
+// Downloads a tile into the cache, returns the expiry the server asked for. Error responses are
+// not written, the tile server may send an error page or nothing in place of the image.
+pub fn fetch_tile(url: &str, file: &Path) -> Result<Option<u64>, String> {
+    let response = ehttp::fetch_blocking(&tile_request(url))?;
+    if !response.ok {
+        return Err(format!(
+            "{} returned {} {}",
+            url, response.status, response.status_text
+        ));
+    }
+    store_response(file, &response)
+}
+
+// Periodically trims the cache to its size and saves the index.
+pub fn maintain_tile_cache(time: Res<Time>, mut elapsed: Local<f32>, mut cache: ResMut<TileCache>) {
+    *elapsed += time.delta_seconds();
+    if *elapsed < EVICTION_INTERVAL {
+        return;
+    }
+    *elapsed = 0.0;
+    let evicted = cache.evict();
+    if evicted > 0 {
+        info!("Tile cache full, deleted {} tiles", evicted);
+    }
+    cache.save();
+}
+
+pub fn save_tile_cache_on_exit(mut exits: EventReader<AppExit>, mut cache: ResMut<TileCache>) {
+    if exits.read().next().is_some() {
+        cache.save();
+    }
+}
+
+// Tiles deleted by `mapapp purge`, every tile matching all the given filters.
+#[derive(Default)]
+pub struct PurgeRequest {
+    pub layer: Option<String>,
+    // West, south, east, north in degrees.
+    pub bounds: Option<[f64; 4]>,
+    pub zooms: Option<(u8, u8)>,
+}
+
+impl PurgeRequest {
+    // Parses the purge flags, the remaining flags are returned for the configuration.
+    pub fn parse_args(
+        args: impl IntoIterator<Item = String>,
+    ) -> Result<(Self, Vec<String>), ConfigError> {
+        let mut request = Self::default();
+        let mut remaining = Vec::new();
+        let mut args = args.into_iter();
+        while let Some(flag) = args.next() {
+            let value = args
+                .next()
+                .ok_or_else(|| ConfigError::MissingValue(flag.clone()))?;
+            let invalid = || ConfigError::InvalidValue(flag.clone(), value.clone());
+            match flag.as_str() {
+                "--layer" => request.layer = Some(value),
+                "--bbox" => request.bounds = Some(parse_bounds(&value).ok_or_else(invalid)?),
+                "--zoom" => request.zooms = Some(parse_zoom_range(&value).ok_or_else(invalid)?),
+                _ => remaining.extend([flag, value]),
+            }
+        }
+        Ok((request, remaining))
+    }
+
+    pub fn matches(&self, layer: &str, key: TileKey) -> bool {
+        let in_layer = self.layer.as_deref().is_none_or(|name| name == layer);
+        let in_zooms = self
+            .zooms
+            .is_none_or(|(min, max)| (min..=max).contains(&key.zoom_level));
+        let in_bounds = self.bounds.is_none_or(|bounds| {
+            bounds_to_tiles(bounds, key.zoom_level).contains(UVec2::new(key.x, key.y))
+        });
+        in_layer && in_zooms && in_bounds
+    }
+}
+
+// Entry point of `mapapp purge`, returns the process exit code.
+pub fn run_purge_command(args: Vec<String>) -> i32 {
+    let parsed = PurgeRequest::parse_args(args).and_then(|(request, remaining)| {
+        MapConfig::parse_args(remaining).map(|config| (request, config))
+    });
+    let (request, config) = match parsed {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            eprintln!("Invalid purge request: {}", err);
+            return 2;
+        }
+    };
+
+    let mut cache = TileCache::open(&config);
+    let (count, bytes) = cache.purge(|layer, key| request.matches(layer, key));
+    cache.save();
+    println!(
+        "Purged {} tiles ({:.1} MiB), {:.1} MiB left in the cache",
+        count,
+        bytes as f64 / MEBIBYTE as f64,
+        cache.total_bytes() as f64 / MEBIBYTE as f64
+    );
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&str, &str)]) -> ehttp::Headers {
+        ehttp::Headers::new(pairs)
+    }
+
+    fn cache(max_bytes: u64, entries: &[(&str, u64, u64)]) -> TileCache {
+        TileCache {
+            directory: PathBuf::new(),
+            max_bytes,
+            default_max_age: 0,
+            entries: entries
+                .iter()
+                .map(|&(path, bytes, last_access)| {
+                    let entry = CacheEntry {
+                        bytes,
+                        last_access,
+                        expires: u64::MAX,
+                    };
+                    (path.to_string(), entry)
+                })
+                .collect(),
+            dirty: false,
+        }
+    }
+
+    #[test]
+    fn http_dates_are_parsed_to_unix_time() {
+        assert_eq!(
+            parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"),
+            Some(784_111_777)
+        );
+        assert_eq!(parse_http_date("Thu, 01 Jan 1970 00:00:00 GMT"), Some(0));
+        assert_eq!(
+            parse_http_date("Tue, 29 Feb 2000 12:00:00 GMT"),
+            Some(951_825_600)
+        );
+        assert_eq!(parse_http_date("0"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 CET"), None);
+        assert_eq!(parse_http_date("Sun, 06 Foo 1994 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49 GMT"), None);
+    }
+
+    #[test]
+    fn response_expiry_follows_cache_control_before_expires() {
+        let expires = "Sun, 06 Nov 1994 08:49:37 GMT";
+        let expiry = |pairs: &[(&str, &str)]| response_expiry(&headers(pairs));
+
+        let before = now();
+        let max_age = expiry(&[("Cache-Control", "public, max-age=3600")]).unwrap();
+        assert!((before + 3600..=now() + 3600).contains(&max_age));
+        let max_age = expiry(&[("Cache-Control", "max-age=60"), ("Expires", expires)]).unwrap();
+        assert!(max_age >= before + 60);
+
+        let no_store = expiry(&[("Cache-Control", "no-store"), ("Expires", expires)]).unwrap();
+        assert!((before..=now()).contains(&no_store));
+        assert_eq!(
+            expiry(&[("Cache-Control", "public"), ("Expires", expires)]),
+            Some(784_111_777)
+        );
+        assert!(expiry(&[("Expires", "0")]).unwrap() >= before);
+        assert_eq!(expiry(&[]), None);
+    }
+
+    #[test]
+    fn evict_deletes_the_least_recently_displayed_tiles() {
+        // The files do not exist, their entries are forgotten as if deleted.
+        let mut cache = cache(
+            250,
+            &[
+                ("test-cache/a/1.0.0.256.tile.png", 100, 30),
+                ("test-cache/a/1.0.1.256.tile.png", 100, 10),
+                ("test-cache/a/1.1.0.256.tile.png", 100, 20),
+                ("test-cache/a/1.1.1.256.tile.png", 100, 40),
+            ],
+        );
+        assert_eq!(cache.evict(), 2);
+        let mut left: Vec<&str> = cache.entries.keys().map(String::as_str).collect();
+        left.sort();
+        assert_eq!(
+            left,
+            [
+                "test-cache/a/1.0.0.256.tile.png",
+                "test-cache/a/1.1.1.256.tile.png"
+            ]
+        );
+        assert!(cache.dirty);
+        assert_eq!(cache.evict(), 0);
+    }
+
+    #[test]
+    fn evict_skips_the_tiles_it_cannot_delete() {
+        // `assets/..` is a directory, deleting it as a file fails.
+        let mut cache = cache(
+            100,
+            &[
+                ("..", 100, 10),
+                ("test-cache/a/1.0.0.256.tile.png", 100, 20),
+            ],
+        );
+        assert_eq!(cache.evict(), 1);
+        assert!(cache.entries.contains_key(".."));
+        assert_eq!(cache.total_bytes(), 100);
+    }
+
+    #[test]
+    fn purge_requests_match_every_given_filter() {
+        let key = |zoom_level, x, y| TileKey { zoom_level, x, y };
+        assert!(PurgeRequest::default().matches("osm", key(3, 1, 2)));
+
+        let (request, remaining) = PurgeRequest::parse_args(
+            [
+                "--layer",
+                "osm",
+                "--bbox",
+                "0,0,10,10",
+                "--zoom",
+                "2-4",
+                "--cache-size",
+                "10",
+            ]
+            .map(String::from),
+        )
+        .unwrap();
+        assert_eq!(remaining, ["--cache-size", "10"]);
+        // The tile south east of (0, 0) at zoom level 2 is (2, 1).
+        assert!(request.matches("osm", key(2, 2, 1)));
+        assert!(!request.matches("topo", key(2, 2, 1)));
+        assert!(!request.matches("osm", key(1, 1, 0)));
+        assert!(!request.matches("osm", key(5, 16, 15)));
+        assert!(!request.matches("osm", key(2, 0, 0)));
+    }
+}
@@ -0,0 +1,621 @@
+// Tile source configuration, read from a RON file and overridden by command line flags.
+//
+// mapapp [--config <file.ron>] [--url <template>] [--cache-dir <dir>] [--tile-size <normal|large>]
+//        [--max-zoom <level>] [--attribution <text>] [--cache-size <MiB>] [--cache-max-age <seconds>]
+//        [--fade-duration <seconds>] [--low-power <true|false>] [--units <metric|imperial>]
+//        [--go-to <latitude,longitude[,zoom]>] [--gazetteer <places.tsv>] [--geocoder-url <url>]
+//        [--geojson <file.geojson>]...
+//
+// Without `--config`, `mapapp.ron` in the working directory is used when it exists.
+// `--url`, `--max-zoom` and `--attribution` apply to the first base layer.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use bevy::prelude::*;
+use serde::Deserialize;
+
+use crate::fly_to::parse_location;
+use crate::projection::MAX_ZOOM_LEVEL;
+
+const DEFAULT_CONFIG_FILE: &str = "mapapp.ron";
+// Sent with every request, tile usage policies ask for an identifying User-Agent.
+pub const USER_AGENT: &str = concat!("mapapp/", env!("CARGO_PKG_VERSION"));
+// Folder the asset server loads from, the tile cache directories are relative to it.
+pub const ASSETS_DIRECTORY: &str = "assets";
+// Tile servers of the default layers, they serve no `@2x` tiles.
+const NORMAL_TILE_SERVERS: [&str; 2] = ["tile.openstreetmap.org", "tile.opentopomap.org"];
+
+// Pixel size of the downloaded tiles, large tiles cover the area of a normal tile in twice the
+// pixels for high density displays.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TileSize {
+    Normal,
+    Large,
+}
+
+impl TileSize {
+    pub fn to_pixels(self) -> u32 {
+        match self {
+            TileSize::Normal => 256,
+            TileSize::Large => 512,
+        }
+    }
+}
+
+// Units of the distances shown on the map.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Units {
+    Metric,
+    Imperial,
+}
+
+// A tile source the map can display.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LayerConfig {
+    // Unique name, also the cache subdirectory of the layer.
+    pub name: String,
+    // Tile URL with `{z}`, `{x}` and `{y}` placeholders, and optionally `{r}`, see
+    // `sized_url_template`.
+    pub url_template: String,
+    // Highest zoom level the tile server provides, the map is magnified beyond it.
+    pub max_zoom: u8,
+    pub attribution: String,
+    // MBTiles file the tiles are read from instead of the tile server.
+    pub mbtiles: Option<String>,
+    // PMTiles archive the tiles are read from instead of the tile server.
+    pub pmtiles: Option<String>,
+}
+
+impl Default for LayerConfig {
+    fn default() -> Self {
+        Self {
+            name: "osm".into(),
+            url_template: "https://tile.openstreetmap.org/{z}/{x}/{y}.png".into(),
+            max_zoom: MAX_ZOOM_LEVEL,
+            attribution: "© OpenStreetMap contributors".into(),
+            mbtiles: None,
+            pmtiles: None,
+        }
+    }
+}
+
+impl LayerConfig {
+    // URL template of the tiles of the given size: `{r}` becomes `@2x` for large tiles, the suffix
+    // most servers give their high density tiles, and is dropped for normal ones. Templates
+    // without it are expected to serve tiles of the configured size.
+    pub fn sized_url_template(&self, tile_size: TileSize) -> String {
+        let postfix = match tile_size {
+            TileSize::Normal => "",
+            TileSize::Large => "@2x",
+        };
+        self.url_template.replace("{r}", postfix)
+    }
+}
+
+// A transparent tile source drawn over the base layer.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OverlayConfig {
+    // Unique name, also the cache subdirectory of the overlay.
+    pub name: String,
+    // Tile URL with `{z}`, `{x}` and `{y}` placeholders.
+    pub url_template: String,
+    pub max_zoom: u8,
+    pub attribution: String,
+    // Overlays with a higher z-order are drawn on top.
+    pub z_order: i32,
+    // Alpha applied to the overlay tiles, from 0 (invisible) to 1 (opaque).
+    pub opacity: f32,
+    // Whether the overlay is shown on startup.
+    pub visible: bool,
+}
+
+impl Default for OverlayConfig {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            url_template: String::new(),
+            max_zoom: MAX_ZOOM_LEVEL,
+            attribution: String::new(),
+            z_order: 0,
+            opacity: 1.0,
+            visible: true,
+        }
+    }
+}
+
+impl OverlayConfig {
+    pub fn tile_url(&self, zoom_level: u8, x: u32, y: u32) -> String {
+        tile_url(&self.url_template, zoom_level, x, y)
+    }
+}
+
+#[derive(Resource, Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MapConfig {
+    // Folder under `assets/` storing the downloaded tiles, one subdirectory per layer.
+    pub cache_directory: String,
+    // Size of the tile cache in MiB, the least recently displayed tiles are deleted beyond it.
+    pub cache_size: u64,
+    // Seconds before a cached tile is downloaded again, when its server sent no expiry.
+    pub cache_max_age: u64,
+    pub tile_size: TileSize,
+    // Base layers to switch between at runtime, the first one is shown on startup.
+    pub base_layers: Vec<LayerConfig>,
+    // Overlays drawn over the base layer, each can be toggled and faded on its own.
+    pub overlays: Vec<OverlayConfig>,
+    // Seconds tiles take to fade in once loaded, and finer zoom levels to fade out.
+    pub fade_duration: f32,
+    // Skips the animations that are not needed to use the map, like the tile fades.
+    pub low_power: bool,
+    pub units: Units,
+    // Latitude, longitude and zoom level the map flies to at startup.
+    pub go_to: Option<(f64, f64, u8)>,
+    // GeoNames dump or TSV place extract searched by the go-to box, see `gazetteer.rs`.
+    pub gazetteer: Option<String>,
+    // Nominatim server searched for the places the gazetteer does not know, see `nominatim.rs`.
+    pub geocoder_url: Option<String>,
+    // GeoJSON files drawn over the map, see `geojson.rs`.
+    pub geojson: Vec<String>,
+}
+
+impl Default for MapConfig {
+    fn default() -> Self {
+        Self {
+            cache_directory: "tiles/".into(),
+            cache_size: 1024,
+            cache_max_age: 7 * 24 * 60 * 60,
+            tile_size: TileSize::Normal,
+            base_layers: vec![
+                LayerConfig::default(),
+                LayerConfig {
+                    name: "topo".into(),
+                    url_template: "https://tile.opentopomap.org/{z}/{x}/{y}.png".into(),
+                    max_zoom: 17,
+                    attribution: "© OpenStreetMap contributors, SRTM | © OpenTopoMap (CC-BY-SA)"
+                        .into(),
+                    mbtiles: None,
+                    pmtiles: None,
+                },
+            ],
+            overlays: Vec::new(),
+            fade_duration: 0.3,
+            low_power: false,
+            units: Units::Metric,
+            go_to: None,
+            gazetteer: None,
+            geocoder_url: None,
+            geojson: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, ron::error::SpannedError),
+    MissingValue(String),
+    UnknownFlag(String),
+    InvalidValue(String, String),
+    NoBaseLayers,
+    DuplicateLayer(String),
+    MissingPlaceholders(String),
+    MultipleSources(String),
+    UnsupportedTileSize(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, err) => write!(f, "cannot read {}: {}", path.display(), err),
+            ConfigError::Parse(path, err) => write!(f, "cannot parse {}: {}", path.display(), err),
+            ConfigError::MissingValue(flag) => write!(f, "missing value for {}", flag),
+            ConfigError::UnknownFlag(flag) => write!(f, "unknown flag {}", flag),
+            ConfigError::InvalidValue(flag, value) => {
+                write!(f, "invalid value {:?} for {}", value, flag)
+            }
+            ConfigError::NoBaseLayers => write!(f, "at least one base layer is required"),
+            ConfigError::DuplicateLayer(name) => write!(f, "layer {:?} is defined twice", name),
+            ConfigError::MissingPlaceholders(template) => write!(
+                f,
+                "url template {:?} must contain {{z}}, {{x}} and {{y}}",
+                template
+            ),
+            ConfigError::MultipleSources(name) => {
+                write!(
+                    f,
+                    "layer {:?} has both an MBTiles and a PMTiles archive",
+                    name
+                )
+            }
+            ConfigError::UnsupportedTileSize(name) => write!(
+                f,
+                "layer {:?} has no large tiles, use tile_size: normal",
+                name
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl MapConfig {
+    pub fn from_args() -> Result<Self, ConfigError> {
+        Self::parse_args(std::env::args().skip(1))
+    }
+
+    pub fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Self, ConfigError> {
+        let mut flags = Vec::new();
+        let mut config_file = None;
+        let mut args = args.into_iter();
+        while let Some(flag) = args.next() {
+            let value = args
+                .next()
+                .ok_or_else(|| ConfigError::MissingValue(flag.clone()))?;
+            if flag == "--config" {
+                config_file = Some(PathBuf::from(value));
+            } else {
+                flags.push((flag, value));
+            }
+        }
+
+        // Flags override the values from the config file.
+        let mut config = match config_file {
+            Some(path) => Self::load(&path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                Self::load(Path::new(DEFAULT_CONFIG_FILE))?
+            }
+            None => Self::default(),
+        };
+        for (flag, value) in flags {
+            config.apply_flag(&flag, value)?;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|err| ConfigError::Read(path.to_path_buf(), err))?;
+        ron::from_str(&text).map_err(|err| ConfigError::Parse(path.to_path_buf(), err))
+    }
+
+    fn apply_flag(&mut self, flag: &str, value: String) -> Result<(), ConfigError> {
+        let invalid = || ConfigError::InvalidValue(flag.to_string(), value.clone());
+        match flag {
+            "--url" => self.base_layer_mut()?.url_template = value,
+            "--cache-dir" => self.cache_directory = value,
+            "--cache-size" => self.cache_size = value.parse().map_err(|_| invalid())?,
+            "--cache-max-age" => self.cache_max_age = value.parse().map_err(|_| invalid())?,
+            "--tile-size" => {
+                self.tile_size = match value.as_str() {
+                    "normal" | "256" => TileSize::Normal,
+                    "large" | "512" => TileSize::Large,
+                    _ => return Err(invalid()),
+                }
+            }
+            "--max-zoom" => {
+                self.base_layer_mut()?.max_zoom = value.parse().map_err(|_| invalid())?
+            }
+            "--attribution" => self.base_layer_mut()?.attribution = value,
+            "--fade-duration" => self.fade_duration = value.parse().map_err(|_| invalid())?,
+            "--low-power" => self.low_power = value.parse().map_err(|_| invalid())?,
+            "--go-to" => {
+                let location = parse_location(&value).ok_or_else(invalid)?;
+                self.go_to = Some((
+                    location.lat_lon.latitude,
+                    location.lat_lon.longitude,
+                    location.zoom_level,
+                ));
+            }
+            "--gazetteer" => self.gazetteer = Some(value),
+            "--geocoder-url" => self.geocoder_url = Some(value),
+            "--geojson" => self.geojson.push(value),
+            "--units" => {
+                self.units = match value.as_str() {
+                    "metric" => Units::Metric,
+                    "imperial" => Units::Imperial,
+                    _ => return Err(invalid()),
+                }
+            }
+            _ => return Err(ConfigError::UnknownFlag(flag.to_string())),
+        }
+        Ok(())
+    }
+
+    fn base_layer_mut(&mut self) -> Result<&mut LayerConfig, ConfigError> {
+        self.base_layers
+            .first_mut()
+            .ok_or(ConfigError::NoBaseLayers)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.base_layers.is_empty() {
+            return Err(ConfigError::NoBaseLayers);
+        }
+        // Names double as cache subdirectories, so they are unique across base layers and overlays.
+        let names: Vec<&str> = self
+            .base_layers
+            .iter()
+            .map(|layer| layer.name.as_str())
+            .chain(self.overlays.iter().map(|overlay| overlay.name.as_str()))
+            .collect();
+        for (index, name) in names.iter().enumerate() {
+            if names[..index].contains(name) {
+                return Err(ConfigError::DuplicateLayer(name.to_string()));
+            }
+        }
+
+        if self.fade_duration.is_nan() || self.fade_duration < 0.0 {
+            return Err(ConfigError::InvalidValue(
+                "fade_duration".into(),
+                self.fade_duration.to_string(),
+            ));
+        }
+
+        if let Some((latitude, longitude, zoom_level)) = self.go_to {
+            if latitude.abs() > 90.0 || longitude.abs() > 180.0 || zoom_level > MAX_ZOOM_LEVEL {
+                return Err(ConfigError::InvalidValue(
+                    "go_to".into(),
+                    format!("{:?}", self.go_to),
+                ));
+            }
+        }
+        if let Some(url) = &self.geocoder_url {
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                return Err(ConfigError::InvalidValue(
+                    "geocoder_url".into(),
+                    url.clone(),
+                ));
+            }
+        }
+
+        let max_zoom = |name: &str, max_zoom: u8| {
+            if max_zoom > MAX_ZOOM_LEVEL {
+                return Err(ConfigError::InvalidValue(
+                    format!("{}.max_zoom", name),
+                    max_zoom.to_string(),
+                ));
+            }
+            Ok(())
+        };
+        for layer in &self.base_layers {
+            max_zoom(&layer.name, layer.max_zoom)?;
+            match (&layer.mbtiles, &layer.pmtiles) {
+                (None, None) => {
+                    check_placeholders(&layer.url_template)?;
+                    if self.tile_size == TileSize::Large
+                        && NORMAL_TILE_SERVERS
+                            .iter()
+                            .any(|server| layer.url_template.contains(server))
+                    {
+                        return Err(ConfigError::UnsupportedTileSize(layer.name.clone()));
+                    }
+                }
+                (Some(_), Some(_)) => return Err(ConfigError::MultipleSources(layer.name.clone())),
+                _ => {}
+            }
+        }
+        for overlay in &self.overlays {
+            max_zoom(&overlay.name, overlay.max_zoom)?;
+            if !(0.0..=1.0).contains(&overlay.opacity) {
+                return Err(ConfigError::InvalidValue(
+                    format!("{}.opacity", overlay.name),
+                    overlay.opacity.to_string(),
+                ));
+            }
+            check_placeholders(&overlay.url_template)?;
+        }
+        Ok(())
+    }
+
+    pub fn fades_enabled(&self) -> bool {
+        !self.low_power && self.fade_duration > 0.0
+    }
+
+    // Folder under `assets/` storing the tiles of a base layer or overlay.
+    pub fn layer_directory(&self, name: &str) -> String {
+        format!("{}/{}/", self.cache_directory.trim_end_matches('/'), name)
+    }
+}
+
+fn check_placeholders(url_template: &str) -> Result<(), ConfigError> {
+    if ["{z}", "{x}", "{y}"]
+        .iter()
+        .any(|placeholder| !url_template.contains(placeholder))
+    {
+        return Err(ConfigError::MissingPlaceholders(url_template.to_string()));
+    }
+    Ok(())
+}
+
+// File name of a cached tile, the layout of the slippy tiles plugin so its caches stay valid.
+pub fn tile_file_name(zoom_level: u8, x: u32, y: u32, tile_size: TileSize) -> String {
+    format!(
+        "{}.{}.{}.{}.tile.png",
+        zoom_level,
+        x,
+        y,
+        tile_size.to_pixels()
+    )
+}
+
+// Tile URL of a `{z}/{x}/{y}` template.
+pub fn tile_url(url_template: &str, zoom_level: u8, x: u32, y: u32) -> String {
+    url_template
+        .replace("{z}", &zoom_level.to_string())
+        .replace("{x}", &x.to_string())
+        .replace("{y}", &y.to_string())
+}
+
+// GET request of a tile, identifying the application.
+pub fn tile_request(url: &str) -> ehttp::Request {
+    let mut request = ehttp::Request::get(url);
+    request.headers.insert("User-Agent", USER_AGENT);
+    request
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<MapConfig, ConfigError> {
+        MapConfig::parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    fn with_url(url_template: &str) -> Result<MapConfig, ConfigError> {
+        parse(&["--url", url_template])
+    }
+
+    #[test]
+    fn ron_files_fill_in_the_missing_values() {
+        let config: MapConfig = ron::from_str(
+            r#"(
+                tile_size: large,
+                base_layers: [(
+                    name: "satellite",
+                    url_template: "https://tiles.example.com/{z}/{x}/{y}{r}.jpg",
+                    max_zoom: 18,
+                )],
+                overlays: [(name: "trails", url_template: "https://trails.example.com/{z}/{x}/{y}.png", opacity: 0.5)],
+            )"#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+        assert_eq!(config.tile_size, TileSize::Large);
+        assert_eq!(config.cache_directory, "tiles/");
+        assert_eq!(config.base_layers.len(), 1);
+        let layer = &config.base_layers[0];
+        assert_eq!(layer.max_zoom, 18);
+        assert_eq!(layer.attribution, LayerConfig::default().attribution);
+        assert_eq!(
+            layer.sized_url_template(TileSize::Large),
+            "https://tiles.example.com/{z}/{x}/{y}@2x.jpg"
+        );
+        assert_eq!(
+            layer.sized_url_template(TileSize::Normal),
+            "https://tiles.example.com/{z}/{x}/{y}.jpg"
+        );
+        assert_eq!(config.overlays[0].opacity, 0.5);
+        assert!(config.overlays[0].visible);
+
+        assert!(ron::from_str::<MapConfig>("(tile_sise: large)").is_err());
+    }
+
+    #[test]
+    fn flags_override_the_config_file() {
+        let path = std::env::temp_dir().join(format!("mapapp-test-{}.ron", std::process::id()));
+        std::fs::write(&path, "(cache_size: 10, low_power: true)").unwrap();
+        let config = parse(&[
+            "--cache-size",
+            "20",
+            "--config",
+            path.to_str().unwrap(),
+            "--max-zoom",
+            "12",
+            "--tile-size",
+            "256",
+        ]);
+        std::fs::remove_file(&path).unwrap();
+        let config = config.unwrap();
+        assert_eq!(config.cache_size, 20);
+        assert!(config.low_power);
+        assert_eq!(config.tile_size, TileSize::Normal);
+        assert_eq!(config.base_layers[0].max_zoom, 12);
+        assert_eq!(config.base_layers[1].max_zoom, 17);
+
+        assert!(matches!(
+            parse(&["--config", "/nonexistent/mapapp.ron"]),
+            Err(ConfigError::Read(..))
+        ));
+    }
+
+    #[test]
+    fn url_templates_need_every_placeholder() {
+        assert!(with_url("https://tiles.example.com/{z}/{x}/{y}.jpg").is_ok());
+        assert!(with_url("https://tiles.example.com/{z}/{x}/{y}.png?key=abc").is_ok());
+        assert!(with_url("https://tiles.example.com/tile?z={z}&y={y}&x={x}").is_ok());
+        assert!(matches!(
+            with_url("https://tiles.example.com/{z}/{x}.png"),
+            Err(ConfigError::MissingPlaceholders(_))
+        ));
+
+        let mut config = MapConfig::default();
+        config.overlays.push(OverlayConfig {
+            name: "trails".into(),
+            url_template: "https://trails.example.com/{x}/{y}.png".into(),
+            ..default()
+        });
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::MissingPlaceholders(_))
+        ));
+    }
+
+    #[test]
+    fn invalid_flags_and_values_are_rejected() {
+        assert!(matches!(
+            parse(&["--zoom", "3"]),
+            Err(ConfigError::UnknownFlag(flag)) if flag == "--zoom"
+        ));
+        assert!(matches!(
+            parse(&["--cache-size"]),
+            Err(ConfigError::MissingValue(flag)) if flag == "--cache-size"
+        ));
+        assert!(matches!(
+            parse(&["--max-zoom", "25"]),
+            Err(ConfigError::InvalidValue(name, value)) if name == "osm.max_zoom" && value == "25"
+        ));
+        assert!(matches!(
+            parse(&["--max-zoom", "high"]),
+            Err(ConfigError::InvalidValue(..))
+        ));
+        assert!(matches!(
+            parse(&["--tile-size", "1024"]),
+            Err(ConfigError::InvalidValue(..))
+        ));
+        assert!(matches!(
+            parse(&["--fade-duration", "-1"]),
+            Err(ConfigError::InvalidValue(..))
+        ));
+    }
+
+    #[test]
+    fn layers_are_checked_together() {
+        let mut config = MapConfig::default();
+        config.base_layers[1].name = "osm".into();
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::DuplicateLayer(name)) if name == "osm"
+        ));
+
+        // The default servers have no large tiles, unlike archives.
+        assert!(matches!(
+            parse(&["--tile-size", "large"]),
+            Err(ConfigError::UnsupportedTileSize(name)) if name == "osm"
+        ));
+        let mut config = MapConfig {
+            tile_size: TileSize::Large,
+            ..default()
+        };
+        config.base_layers.truncate(1);
+        config.base_layers[0].mbtiles = Some("region.mbtiles".into());
+        assert!(config.validate().is_ok());
+        config.base_layers[0].pmtiles = Some("region.pmtiles".into());
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::MultipleSources(_))
+        ));
+
+        config.base_layers.clear();
+        assert!(matches!(config.validate(), Err(ConfigError::NoBaseLayers)));
+        assert!(matches!(
+            config.apply_flag("--url", String::new()),
+            Err(ConfigError::NoBaseLayers)
+        ));
+    }
+}
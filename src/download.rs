@@ -0,0 +1,272 @@
+// Offline region pre-download, run instead of the map to fill the tile cache before going offline:
+//
+// mapapp download --bbox <west,south,east,north> --zoom <min>[-<max>] [--layer <name>]
+//                 [--rate <requests per second>] [--max-tiles <count>] [configuration flags]
+//
+// Tiles are stored in the same cache layout the map reads, tiles already cached and not expired are
+// skipped.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::cache::{store_response, TileCache};
+use crate::config::{
+    tile_file_name, tile_request, tile_url, ConfigError, MapConfig, TileSize, ASSETS_DIRECTORY,
+};
+use crate::projection::{bounds_to_tiles, MAX_ZOOM_LEVEL};
+
+// Attempts per tile when the server asks to slow down or fails temporarily.
+const MAX_ATTEMPTS: u32 = 4;
+// Wait before retrying when the server does not send a `Retry-After` header.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+pub struct DownloadRequest {
+    // West, south, east, north in degrees.
+    pub bounds: [f64; 4],
+    pub min_zoom: u8,
+    pub max_zoom: u8,
+    // Base layer or overlay to download, the first base layer by default.
+    pub layer: Option<String>,
+    // Requests per second sent to the tile server.
+    pub rate: f64,
+    // Regions with more tiles are refused, bulk downloads are against most tile usage policies.
+    pub max_tiles: u64,
+}
+
+impl DownloadRequest {
+    // Parses the download flags, the remaining flags are returned for the configuration.
+    pub fn parse_args(
+        args: impl IntoIterator<Item = String>,
+    ) -> Result<(Self, Vec<String>), ConfigError> {
+        let mut bounds = None;
+        let mut zooms = None;
+        let mut request = Self {
+            bounds: [0.0; 4],
+            min_zoom: 0,
+            max_zoom: 0,
+            layer: None,
+            rate: 2.0,
+            max_tiles: 10_000,
+        };
+        let mut remaining = Vec::new();
+        let mut args = args.into_iter();
+        while let Some(flag) = args.next() {
+            let value = args
+                .next()
+                .ok_or_else(|| ConfigError::MissingValue(flag.clone()))?;
+            let invalid = || ConfigError::InvalidValue(flag.clone(), value.clone());
+            match flag.as_str() {
+                "--bbox" => bounds = Some(parse_bounds(&value).ok_or_else(invalid)?),
+                "--zoom" => zooms = Some(parse_zoom_range(&value).ok_or_else(invalid)?),
+                "--layer" => request.layer = Some(value),
+                "--rate" => {
+                    request.rate = value.parse().map_err(|_| invalid())?;
+                    if request.rate <= 0.0 {
+                        return Err(invalid());
+                    }
+                }
+                "--max-tiles" => request.max_tiles = value.parse().map_err(|_| invalid())?,
+                _ => remaining.extend([flag, value]),
+            }
+        }
+
+        request.bounds = bounds.ok_or_else(|| ConfigError::MissingValue("--bbox".into()))?;
+        (request.min_zoom, request.max_zoom) =
+            zooms.ok_or_else(|| ConfigError::MissingValue("--zoom".into()))?;
+        Ok((request, remaining))
+    }
+
+    pub fn tile_count(&self) -> u64 {
+        (self.min_zoom..=self.max_zoom)
+            .map(|zoom_level| {
+                let range = bounds_to_tiles(self.bounds, zoom_level);
+                (range.width() as u64 + 1) * (range.height() as u64 + 1)
+            })
+            .sum()
+    }
+}
+
+// `west,south,east,north` in degrees.
+pub fn parse_bounds(value: &str) -> Option<[f64; 4]> {
+    let values: Vec<f64> = value
+        .split(',')
+        .map(|part| part.trim().parse())
+        .collect::<Result<_, _>>()
+        .ok()?;
+    let [west, south, east, north]: [f64; 4] = values.try_into().ok()?;
+    (west < east && south < north).then_some([west, south, east, north])
+}
+
+// `min-max` or a single zoom level.
+pub fn parse_zoom_range(value: &str) -> Option<(u8, u8)> {
+    let (min, max) = value.split_once('-').unwrap_or((value, value));
+    let (min, max): (u8, u8) = (min.parse().ok()?, max.parse().ok()?);
+    (min <= max && max <= MAX_ZOOM_LEVEL).then_some((min, max))
+}
+
+#[derive(Default)]
+pub struct DownloadSummary {
+    pub downloaded: u64,
+    pub cached: u64,
+    pub failed: u64,
+}
+
+// Entry point of `mapapp download`, returns the process exit code.
+pub fn run_command(args: Vec<String>) -> i32 {
+    let parsed = DownloadRequest::parse_args(args).and_then(|(request, remaining)| {
+        MapConfig::parse_args(remaining).map(|config| (request, config))
+    });
+    let (request, config) = match parsed {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            eprintln!("Invalid download request: {}", err);
+            return 2;
+        }
+    };
+    match download_region(&config, &request) {
+        Ok(summary) => {
+            println!(
+                "Done: {} downloaded, {} already cached, {} failed",
+                summary.downloaded, summary.cached, summary.failed
+            );
+            if summary.failed > 0 {
+                1
+            } else {
+                0
+            }
+        }
+        Err(err) => {
+            eprintln!("Download failed: {}", err);
+            1
+        }
+    }
+}
+
+pub fn download_region(
+    config: &MapConfig,
+    request: &DownloadRequest,
+) -> Result<DownloadSummary, String> {
+    // Find the tile url, tile size and cache directory of the layer. Overlays are always
+    // downloaded with normal tiles, see `overlays::download_overlay_tile`.
+    let tile_size = config.tile_size;
+    let (name, url_template, tile_size) = match &request.layer {
+        None => {
+            let layer = &config.base_layers[0];
+            let url_template = layer.sized_url_template(tile_size);
+            (layer.name.clone(), url_template, tile_size)
+        }
+        Some(name) => {
+            let base_layer = config.base_layers.iter().find(|layer| &layer.name == name);
+            let overlay = config.overlays.iter().find(|overlay| &overlay.name == name);
+            match (base_layer, overlay) {
+                (Some(layer), _) if layer.mbtiles.is_some() || layer.pmtiles.is_some() => {
+                    return Err(format!("layer {} is read from an archive", name));
+                }
+                (Some(layer), _) => {
+                    let url_template = layer.sized_url_template(tile_size);
+                    (layer.name.clone(), url_template, tile_size)
+                }
+                (None, Some(overlay)) => (
+                    overlay.name.clone(),
+                    overlay.url_template.clone(),
+                    TileSize::Normal,
+                ),
+                (None, None) => return Err(format!("unknown layer {}", name)),
+            }
+        }
+    };
+    let directory = PathBuf::from(config.layer_directory(&name));
+
+    let total = request.tile_count();
+    if total > request.max_tiles {
+        return Err(format!(
+            "the region has {} tiles, more than the limit of {}, raise it with --max-tiles",
+            total, request.max_tiles
+        ));
+    }
+    println!(
+        "Downloading {} tiles of layer {} into {}",
+        total,
+        name,
+        Path::new(ASSETS_DIRECTORY).join(&directory).display()
+    );
+
+    let interval = Duration::from_secs_f64(1.0 / request.rate);
+    let mut last_request: Option<Instant> = None;
+    let mut cache = TileCache::open(config);
+    let mut summary = DownloadSummary::default();
+    let mut done = 0;
+    for zoom_level in request.min_zoom..=request.max_zoom {
+        let range = bounds_to_tiles(request.bounds, zoom_level);
+        for x in range.min.x..=range.max.x {
+            for y in range.min.y..=range.max.y {
+                done += 1;
+                let path = directory.join(tile_file_name(zoom_level, x, y, tile_size));
+                let file = Path::new(ASSETS_DIRECTORY).join(&path);
+                if file.exists() && !cache.is_expired(&path) {
+                    summary.cached += 1;
+                } else {
+                    let url = tile_url(&url_template, zoom_level, x, y);
+                    match download_tile(&url, &file, interval, &mut last_request) {
+                        Ok(expires) => {
+                            cache.record(&path, expires);
+                            summary.downloaded += 1;
+                        }
+                        Err(err) => {
+                            eprintln!("\n{}: {}", url, err);
+                            summary.failed += 1;
+                        }
+                    }
+                }
+                print!("\r[{}/{}] zoom level {}", done, total, zoom_level);
+                std::io::stdout().flush().ok();
+            }
+        }
+    }
+    println!();
+    // The downloaded tiles may push the cache over its size, the oldest ones go first.
+    cache.evict();
+    cache.save();
+    Ok(summary)
+}
+
+// Downloads a tile and returns its expiry, sending at most one request per `interval` and waiting
+// as long as the server asks when it is rate limited or temporarily unavailable.
+fn download_tile(
+    url: &str,
+    file: &Path,
+    interval: Duration,
+    last_request: &mut Option<Instant>,
+) -> Result<Option<u64>, String> {
+    for attempt in 1..=MAX_ATTEMPTS {
+        if let Some(last_request) = last_request {
+            thread::sleep(interval.saturating_sub(last_request.elapsed()));
+        }
+        *last_request = Some(Instant::now());
+
+        let response = ehttp::fetch_blocking(&tile_request(url))?;
+        if response.ok {
+            return store_response(file, &response);
+        }
+        let retry = response.status == 429 || response.status >= 500;
+        if !retry || attempt == MAX_ATTEMPTS {
+            return Err(format!("{} {}", response.status, response.status_text));
+        }
+        let delay = response
+            .headers
+            .get("retry-after")
+            .and_then(|seconds| seconds.trim().parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(RETRY_DELAY * attempt);
+        eprintln!(
+            "\n{} returned {}, retrying in {}s",
+            url,
+            response.status,
+            delay.as_secs()
+        );
+        thread::sleep(delay);
+    }
+    unreachable!()
+}
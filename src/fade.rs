@@ -0,0 +1,116 @@
+// Tile fades: new tiles fade in once their image is loaded instead of popping in, and the tiles of
+// finer zoom levels fade out once the tiles of the current zoom level under them are shown, so
+// zooming in and out cross-fades between the levels. Both are skipped in low power mode.
+
+use bevy::color::Alpha;
+use bevy::prelude::*;
+use bevy::utils::HashSet;
+
+use crate::config::MapConfig;
+use crate::{MapTile, RequestedTiles, TileIndex, TileKey, WorldState};
+
+#[derive(Component)]
+pub struct FadeIn(Timer);
+
+#[derive(Component)]
+pub struct FadeOut(Timer);
+
+// Tiles with their texture and fades.
+type FadingTiles<'a> = (
+    Entity,
+    &'a MapTile,
+    &'a Handle<Image>,
+    &'a mut Sprite,
+    Option<&'a mut FadeIn>,
+    Option<&'a mut FadeOut>,
+);
+
+// Hides the tiles added to the map until they fade in.
+pub fn start_tile_fades(
+    mut commands: Commands,
+    mut tiles: Query<(Entity, &mut Sprite), Added<MapTile>>,
+    config: Res<MapConfig>,
+) {
+    if !config.fades_enabled() {
+        return;
+    }
+    for (entity, mut sprite) in &mut tiles {
+        sprite.color.set_alpha(0.0);
+        commands.entity(entity).insert(FadeIn(Timer::from_seconds(
+            config.fade_duration,
+            TimerMode::Once,
+        )));
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn update_tile_fades(
+    mut commands: Commands,
+    mut tiles: Query<FadingTiles>,
+    images: Res<Assets<Image>>,
+    time: Res<Time>,
+    config: Res<MapConfig>,
+    state: Res<WorldState>,
+    mut requested: ResMut<RequestedTiles>,
+    mut index: ResMut<TileIndex>,
+) {
+    let Some(zoom_level) = state.zoom_level else {
+        return;
+    };
+    // Tiles of the current zoom level fully shown, the finer tiles over them can fade out.
+    let shown: HashSet<TileKey> = tiles
+        .iter()
+        .filter(|(_, MapTile(key), texture, _, fade_in, _)| {
+            key.zoom_level == zoom_level && fade_in.is_none() && images.contains(*texture)
+        })
+        .map(|(_, MapTile(key), ..)| *key)
+        .collect();
+
+    for (entity, MapTile(key), texture, mut sprite, fade_in, fade_out) in &mut tiles {
+        if let Some(mut fade_in) = fade_in {
+            // The fade starts once the image is loaded.
+            if images.contains(texture) {
+                fade_in.0.tick(time.delta());
+                sprite.color.set_alpha(fade_in.0.fraction());
+                if fade_in.0.finished() {
+                    commands.entity(entity).remove::<FadeIn>();
+                }
+            }
+            continue;
+        }
+
+        // Zoomed back in before the tile faded out.
+        if key.zoom_level <= zoom_level {
+            if fade_out.is_some() {
+                commands.entity(entity).remove::<FadeOut>();
+                sprite.color.set_alpha(1.0);
+            }
+            continue;
+        }
+
+        let depth = key.zoom_level - zoom_level;
+        let ancestor = TileKey {
+            zoom_level,
+            x: key.x >> depth,
+            y: key.y >> depth,
+        };
+        match fade_out {
+            Some(mut fade_out) => {
+                fade_out.0.tick(time.delta());
+                sprite.color.set_alpha(1.0 - fade_out.0.fraction());
+                if fade_out.0.finished() {
+                    commands.entity(entity).despawn_recursive();
+                    requested.0.remove(key);
+                    index.0.remove(key);
+                }
+            }
+            None if config.fades_enabled() && shown.contains(&ancestor) => {
+                commands.entity(entity).insert(FadeOut(Timer::from_seconds(
+                    config.fade_duration,
+                    TimerMode::Once,
+                )));
+            }
+            None => {}
+        }
+    }
+}
@@ -0,0 +1,101 @@
+// Placeholders for the tiles still loading: the nearest ancestor tile already on the map is cropped
+// to the area of the missing tile and magnified underneath it, so zooming in shows a blurry map
+// instead of a blank one. A placeholder is removed once its tile is displayed and faded in.
+
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+
+use crate::fade::FadeIn;
+use crate::projection::{tile_world_size, WorldOrigin};
+use crate::{MapTile, RequestedTiles, TileIndex, TileKey, WorldState};
+
+// Placeholders are drawn between their tile and the tiles of the zoom level below.
+const PLACEHOLDER_Z_OFFSET: f32 = -0.5;
+
+// Placeholder sprites by the key of the tile they stand in for.
+#[derive(Resource, Default)]
+pub struct Placeholders(HashMap<TileKey, Entity>);
+
+#[allow(clippy::too_many_arguments)]
+pub fn update_placeholders(
+    mut commands: Commands,
+    tiles: Query<(&Handle<Image>, Has<FadeIn>), With<MapTile>>,
+    images: Res<Assets<Image>>,
+    origin: Res<WorldOrigin>,
+    state: Res<WorldState>,
+    requested: Res<RequestedTiles>,
+    index: Res<TileIndex>,
+    mut placeholders: ResMut<Placeholders>,
+) {
+    // Texture and pixel size of a tile on the map, once its image is loaded.
+    let loaded_texture = |key: &TileKey| {
+        let (texture, _) = tiles.get(*index.0.get(key)?).ok()?;
+        let size = images.get(texture)?.size();
+        Some((texture.clone(), size))
+    };
+    // Whether a tile is loaded and done fading in.
+    let shown = |key: &TileKey| {
+        index.0.get(key).is_some_and(|entity| {
+            tiles
+                .get(*entity)
+                .is_ok_and(|(texture, fading)| !fading && images.contains(texture))
+        })
+    };
+
+    // Tiles that arrived, or were unloaded before arriving, no longer need a placeholder.
+    placeholders.0.retain(|key, entity| {
+        let pending = requested.0.contains(key) && !shown(key);
+        if !pending {
+            commands.entity(*entity).despawn();
+        }
+        pending
+    });
+
+    let Some(zoom_level) = state.zoom_level else {
+        return;
+    };
+    for key in requested
+        .0
+        .iter()
+        .filter(|key| key.zoom_level == zoom_level)
+    {
+        if placeholders.0.contains_key(key) || shown(key) {
+            continue;
+        }
+        let Some((depth, texture, size)) = (1..=key.zoom_level).find_map(|depth| {
+            let ancestor = TileKey {
+                zoom_level: key.zoom_level - depth,
+                x: key.x >> depth,
+                y: key.y >> depth,
+            };
+            loaded_texture(&ancestor).map(|(texture, size)| (depth, texture, size))
+        }) else {
+            continue;
+        };
+
+        // Part of the ancestor image covering the tile, the ancestor spans 2^depth tiles per side.
+        let cells = (1_u32 << depth) as f32;
+        let cell_size = size.as_vec2() / cells;
+        let mask = (1_u32 << depth) - 1;
+        let min = Vec2::new((key.x & mask) as f32, (key.y & mask) as f32) * cell_size;
+        let position = origin.tile_to_world(key.x, key.y, key.zoom_level);
+        let tile_size = tile_world_size(key.zoom_level);
+        let placeholder = commands
+            .spawn(SpriteBundle {
+                texture,
+                transform: Transform::from_xyz(
+                    position.x,
+                    position.y,
+                    key.zoom_level as f32 + PLACEHOLDER_Z_OFFSET,
+                ),
+                sprite: Sprite {
+                    rect: Some(Rect::from_corners(min, min + cell_size)),
+                    custom_size: Some(Vec2::new(tile_size, tile_size)),
+                    ..default()
+                },
+                ..default()
+            })
+            .id();
+        placeholders.0.insert(*key, placeholder);
+    }
+}
@@ -0,0 +1,336 @@
+// Animated camera flights to a latitude, longitude and zoom level, started with the `FlyTo` event.
+// The camera follows the path of van Wijk and Nuij, "Smooth and efficient zooming and panning"
+// (2003): long flights zoom out, pan and zoom back in, short ones mostly pan. Dragging,
+// scrolling or the keyboard controls stop the flight.
+
+use bevy::input::mouse::MouseWheel;
+use bevy::math::DVec2;
+use bevy::prelude::*;
+
+use crate::cache::TileCache;
+use crate::config::MapConfig;
+use crate::layers::ActiveBaseLayer;
+use crate::offline::OfflineSources;
+use crate::projection::{
+    lat_lon_to_meters, tile_zoom_level, zoom_level_to_scale, LatLon, WorldOrigin, MAX_LATITUDE,
+    MAX_ZOOM_LEVEL,
+};
+use crate::{download_tile, visible_tiles, DownloadPool, MainCamera, PanInertia, TileKey};
+
+// Curvature of the path, higher values zoom out further on long flights.
+const RHO: f64 = std::f64::consts::SQRT_2;
+// Flight duration per unit of path length, and its bounds, in seconds.
+const SECONDS_PER_PATH_UNIT: f64 = 0.8;
+const MIN_DURATION: f64 = 0.5;
+const MAX_DURATION: f64 = 5.0;
+// Zoom level of locations given without one.
+pub const DEFAULT_ZOOM_LEVEL: u8 = 12;
+
+#[derive(Event, Clone, Copy, Debug)]
+pub struct FlyTo {
+    pub lat_lon: LatLon,
+    pub zoom_level: u8,
+}
+
+// `latitude,longitude[,zoom]` in decimal degrees.
+pub fn parse_location(value: &str) -> Option<FlyTo> {
+    let parts: Vec<&str> = value.split(',').map(str::trim).collect();
+    let (latitude, longitude, zoom_level) = match parts[..] {
+        [latitude, longitude] => (latitude, longitude, None),
+        [latitude, longitude, zoom_level] => (latitude, longitude, Some(zoom_level)),
+        _ => return None,
+    };
+    let lat_lon = LatLon::new(latitude.parse().ok()?, longitude.parse().ok()?);
+    let zoom_level = zoom_level.map_or(Some(DEFAULT_ZOOM_LEVEL), |zoom| zoom.parse().ok())?;
+    let valid = lat_lon.latitude.abs() <= 90.0
+        && lat_lon.longitude.abs() <= 180.0
+        && zoom_level <= MAX_ZOOM_LEVEL;
+    valid.then_some(FlyTo {
+        lat_lon,
+        zoom_level,
+    })
+}
+
+// Path between two views, given by their center in projected meters and the width of the viewport
+// in world units.
+struct ZoomPath {
+    start: DVec2,
+    end: DVec2,
+    start_width: f64,
+    // Distance between the centers, 0 for a zoom in place.
+    distance: f64,
+    r0: f64,
+    // Length of the path, in units of the viewport width.
+    length: f64,
+}
+
+impl ZoomPath {
+    fn new(start: DVec2, start_width: f64, end: DVec2, end_width: f64) -> Self {
+        let distance = start.distance(end);
+        let (rho2, rho4) = (RHO * RHO, RHO.powi(4));
+        if distance < 1e-6 {
+            return Self {
+                start,
+                end,
+                start_width,
+                distance: 0.0,
+                r0: 0.0,
+                length: (end_width / start_width).ln().abs() / RHO,
+            };
+        }
+        let b = |width: f64, sign: f64| {
+            (end_width.powi(2) - start_width.powi(2) + sign * rho4 * distance.powi(2))
+                / (2.0 * width * rho2 * distance)
+        };
+        let r = |b: f64| ((b * b + 1.0).sqrt() - b).ln();
+        let r0 = r(b(start_width, 1.0));
+        let r1 = r(b(end_width, -1.0));
+        Self {
+            start,
+            end,
+            start_width,
+            distance,
+            r0,
+            length: (r1 - r0) / RHO,
+        }
+    }
+
+    // Center and viewport width at `t` from 0 to 1.
+    fn at(&self, t: f64, end_width: f64) -> (DVec2, f64) {
+        if self.distance == 0.0 {
+            let width = self.start_width * (end_width / self.start_width).powf(t);
+            return (self.start, width);
+        }
+        let s = t * self.length;
+        let (rho2, r0) = (RHO * RHO, self.r0);
+        let u = self.start_width / (rho2 * self.distance)
+            * (r0.cosh() * (RHO * s + r0).tanh() - r0.sinh());
+        let width = self.start_width * r0.cosh() / (RHO * s + r0).cosh();
+        (self.start.lerp(self.end, u), width)
+    }
+}
+
+struct Flight {
+    path: ZoomPath,
+    end_width: f64,
+    duration: f64,
+    elapsed: f64,
+}
+
+#[derive(Resource, Default)]
+pub struct ActiveFlight(Option<Flight>);
+
+impl ActiveFlight {
+    // Stops the flight where the camera is, for the controls moving the camera themselves.
+    pub fn cancel(&mut self) {
+        if self.0.take().is_some() {
+            info!("Flight interrupted");
+        }
+    }
+}
+
+// Starts a flight for the last `FlyTo` event and requests the tiles at the destination so they
+// download while the camera is on its way.
+#[allow(clippy::too_many_arguments)]
+pub fn start_flights(
+    mut commands: Commands,
+    mut events: EventReader<FlyTo>,
+    cameras: Query<(&Transform, &OrthographicProjection), With<MainCamera>>,
+    windows: Query<&Window>,
+    origin: Res<WorldOrigin>,
+    config: Res<MapConfig>,
+    active_layer: Res<ActiveBaseLayer>,
+    offline: Res<OfflineSources>,
+    mut flight: ResMut<ActiveFlight>,
+    mut inertia: ResMut<PanInertia>,
+    cache: Res<TileCache>,
+    pool: Res<DownloadPool>,
+) {
+    let Some(fly_to) = events.read().last() else {
+        return;
+    };
+    let (camera, projection) = cameras.single();
+    let window = windows.single();
+    let lat_lon = LatLon::new(
+        fly_to.lat_lon.latitude.clamp(-MAX_LATITUDE, MAX_LATITUDE),
+        fly_to.lat_lon.longitude,
+    );
+    let end = lat_lon_to_meters(lat_lon);
+    let end_scale = zoom_level_to_scale(fly_to.zoom_level);
+    info!(
+        "Flying to {:.5}, {:.5} at zoom level {}",
+        lat_lon.latitude, lat_lon.longitude, fly_to.zoom_level
+    );
+
+    let width = window.width() as f64;
+    let path = ZoomPath::new(
+        origin.world_to_meters(camera.translation.truncate()),
+        projection.scale as f64 * width,
+        end,
+        end_scale as f64 * width,
+    );
+    let duration = (path.length.abs() * SECONDS_PER_PATH_UNIT).clamp(MIN_DURATION, MAX_DURATION);
+    flight.0 = Some(Flight {
+        path,
+        end_width: end_scale as f64 * width,
+        duration,
+        elapsed: 0.0,
+    });
+    inertia.velocity = Vec2::ZERO;
+
+    // Tiles of archive layers are read locally, there is nothing to download ahead.
+    let layer = active_layer.layer(&config);
+    if offline.0.contains_key(&layer.name) {
+        return;
+    }
+    let zoom_level = tile_zoom_level(end_scale, config.tile_size).min(layer.max_zoom);
+    let destination = Transform::from_translation(origin.meters_to_world(end).extend(0.0));
+    let destination_projection = OrthographicProjection {
+        scale: end_scale,
+        ..default()
+    };
+    let tiles = visible_tiles(
+        &origin,
+        &destination,
+        &destination_projection,
+        window,
+        zoom_level,
+        0,
+    );
+    for x in tiles.min.x..=tiles.max.x {
+        for y in tiles.min.y..=tiles.max.y {
+            // Tiles already cached and fresh are not downloaded again.
+            let key = TileKey { zoom_level, x, y };
+            let download = download_tile(&pool, &config, &cache, &active_layer, key);
+            if download.fetch {
+                commands.spawn(download);
+            }
+        }
+    }
+}
+
+// Moves the camera along the flight path, eased in and out.
+pub fn update_flight(
+    mut cameras: Query<(&mut Transform, &mut OrthographicProjection), With<MainCamera>>,
+    windows: Query<&Window>,
+    mouse_buttons: Res<ButtonInput<MouseButton>>,
+    mut wheel_events: EventReader<MouseWheel>,
+    time: Res<Time>,
+    origin: Res<WorldOrigin>,
+    mut active: ResMut<ActiveFlight>,
+) {
+    let scrolled = wheel_events.read().count() > 0;
+    let Some(flight) = &mut active.0 else {
+        return;
+    };
+    // The click starting a flight, on a search result, does not stop it.
+    let clicked = mouse_buttons.just_pressed(MouseButton::Left) && flight.elapsed > 0.0;
+    if scrolled || clicked {
+        active.cancel();
+        return;
+    }
+
+    flight.elapsed += time.delta_seconds_f64();
+    let t = (flight.elapsed / flight.duration).min(1.0);
+    let eased = if t < 0.5 {
+        4.0 * t.powi(3)
+    } else {
+        1.0 - (2.0 - 2.0 * t).powi(3) / 2.0
+    };
+    let (center, width) = if t < 1.0 {
+        flight.path.at(eased, flight.end_width)
+    } else {
+        (flight.path.end, flight.end_width)
+    };
+    let (mut transform, mut projection) = cameras.single_mut();
+    let center = origin.meters_to_world(center);
+    transform.translation.x = center.x;
+    transform.translation.y = center.y;
+    projection.scale = (width / windows.single().width() as f64) as f32;
+    if t >= 1.0 {
+        active.0 = None;
+    }
+}
+
+// Flies to the location given in the configuration once the map is set up.
+pub fn fly_to_configured_location(config: Res<MapConfig>, mut fly_to: EventWriter<FlyTo>) {
+    if let Some((latitude, longitude, zoom_level)) = config.go_to {
+        fly_to.send(FlyTo {
+            lat_lon: LatLon::new(latitude, longitude),
+            zoom_level,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(value: f64, expected: f64) {
+        assert!(
+            (value - expected).abs() < 1e-6 * expected.abs().max(1.0),
+            "{} != {}",
+            value,
+            expected
+        );
+    }
+
+    #[test]
+    fn zoom_paths_start_and_end_at_the_views() {
+        let (start, end) = (DVec2::new(-2000.0, 500.0), DVec2::new(6000.0, -1500.0));
+        let path = ZoomPath::new(start, 1000.0, end, 250.0);
+        let (center, width) = path.at(0.0, 250.0);
+        assert_close(center.distance(start), 0.0);
+        assert_close(width, 1000.0);
+        let (center, width) = path.at(1.0, 250.0);
+        assert_close(center.distance(end), 0.0);
+        assert_close(width, 250.0);
+    }
+
+    #[test]
+    fn zoom_paths_follow_the_van_wijk_formulas() {
+        // Between views of the same width w at distance d, b0 = -b1 = ρ²d / 2w so r1 = -r0 and
+        // S = -2r0 / ρ. The camera zooms out to w·cosh(r0) halfway.
+        let (width, distance) = (100.0, 1000.0);
+        let path = ZoomPath::new(DVec2::ZERO, width, DVec2::new(distance, 0.0), width);
+        let b = RHO * RHO * distance / (2.0 * width);
+        let r0 = ((b * b + 1.0).sqrt() - b).ln();
+        assert_close(path.r0, r0);
+        assert_close(path.length, -2.0 * r0 / RHO);
+        let (center, halfway_width) = path.at(0.5, width);
+        assert_close(center.x, distance / 2.0);
+        assert_close(halfway_width, width * r0.cosh());
+        assert!(halfway_width > width);
+
+        // Zooming in place scales the width exponentially, S = |ln(w1 / w0)| / ρ.
+        let path = ZoomPath::new(DVec2::ONE, 800.0, DVec2::ONE, 200.0);
+        assert_close(path.length, 4_f64.ln() / RHO);
+        let (center, width) = path.at(0.5, 200.0);
+        assert_eq!(center, DVec2::ONE);
+        assert_close(width, 400.0);
+    }
+
+    #[test]
+    fn locations_have_an_optional_zoom_level() {
+        let location = parse_location("60.1699, 24.9384").unwrap();
+        assert_eq!(location.lat_lon.latitude, 60.1699);
+        assert_eq!(location.lat_lon.longitude, 24.9384);
+        assert_eq!(location.zoom_level, DEFAULT_ZOOM_LEVEL);
+        assert_eq!(parse_location("-33.87,151.21,5").unwrap().zoom_level, 5);
+        assert!(parse_location("90,-180,0").is_some());
+
+        for invalid in [
+            "91,0",
+            "0,181",
+            "0,0,20",
+            "0,0,-1",
+            "north,east",
+            "60.17",
+            "1,2,3,4",
+            "1,2,x",
+            "",
+        ] {
+            assert!(parse_location(invalid).is_none(), "{:?}", invalid);
+        }
+    }
+}
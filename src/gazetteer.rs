@@ -0,0 +1,407 @@
+// Offline geocoder: places read from a gazetteer file and searched by name, without network
+// access. Two formats are read:
+// - GeoNames dumps (`cities15000.txt`, `allCountries.txt`, ...), tab separated without header,
+//   the alternate names are searched too.
+// - A tab separated file with a `name`, `latitude` and `longitude` header, and optionally
+//   `population` and `country` columns, like an OSM place extract.
+//
+// Names match by prefix, ignoring case, with a few typos allowed, the most populated places first.
+// Large gazetteers have millions of names, the go-to box searches them on the compute task pool.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use bevy::prelude::*;
+use bevy::tasks::{block_on, futures_lite::future, IoTaskPool, Task};
+use bevy::utils::HashMap;
+
+use crate::config::MapConfig;
+use crate::projection::LatLon;
+
+// GeoNames columns.
+const GEONAMES_NAME: usize = 1;
+const GEONAMES_ASCII_NAME: usize = 2;
+const GEONAMES_ALTERNATE_NAMES: usize = 3;
+const GEONAMES_LATITUDE: usize = 4;
+const GEONAMES_LONGITUDE: usize = 5;
+const GEONAMES_COUNTRY: usize = 8;
+const GEONAMES_POPULATION: usize = 14;
+// Queries this long allow one typo, twice as long two.
+const FUZZY_QUERY_LENGTH: usize = 4;
+
+#[derive(Debug)]
+pub enum GazetteerError {
+    Read(PathBuf, std::io::Error),
+    MissingColumn(&'static str),
+    InvalidLine(usize, String),
+}
+
+impl fmt::Display for GazetteerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GazetteerError::Read(path, err) => {
+                write!(f, "cannot read {}: {}", path.display(), err)
+            }
+            GazetteerError::MissingColumn(column) => write!(f, "missing column {}", column),
+            GazetteerError::InvalidLine(line, text) => {
+                write!(f, "invalid line {}: {:?}", line, text)
+            }
+        }
+    }
+}
+
+impl std::error::Error for GazetteerError {}
+
+#[derive(Clone, Debug)]
+pub struct Place {
+    pub name: String,
+    // Country code, empty when unknown.
+    pub country: String,
+    pub lat_lon: LatLon,
+    pub population: u64,
+}
+
+impl Place {
+    pub fn label(&self) -> String {
+        if self.country.is_empty() {
+            self.name.clone()
+        } else {
+            format!("{}, {}", self.name, self.country)
+        }
+    }
+}
+
+pub struct PlaceIndex {
+    places: Vec<Place>,
+    // Normalized names and alternate names with their place, sorted for prefix searches.
+    names: Vec<(String, u32)>,
+}
+
+impl PlaceIndex {
+    pub fn load(path: &Path) -> Result<Self, GazetteerError> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|err| GazetteerError::Read(path.to_path_buf(), err))?;
+        Self::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, GazetteerError> {
+        let mut lines = text.lines().enumerate().peekable();
+        let header = match lines.peek() {
+            Some((_, line)) if line.split('\t').any(|column| column == "name") => {
+                let header = Header::new(line)?;
+                lines.next();
+                Some(header)
+            }
+            _ => None,
+        };
+
+        let mut places = Vec::new();
+        let mut names = Vec::new();
+        for (number, line) in lines.filter(|(_, line)| !line.trim().is_empty()) {
+            let columns: Vec<&str> = line.split('\t').collect();
+            let invalid = || GazetteerError::InvalidLine(number + 1, line.to_string());
+            let (place, alternate_names) = match &header {
+                Some(header) => (header.parse(&columns).ok_or_else(invalid)?, vec![]),
+                None => parse_geonames(&columns).ok_or_else(invalid)?,
+            };
+
+            let index = places.len() as u32;
+            let mut place_names: Vec<String> = std::iter::once(place.name.as_str())
+                .chain(alternate_names)
+                .map(normalize)
+                .filter(|name| !name.is_empty())
+                .collect();
+            place_names.sort();
+            place_names.dedup();
+            names.extend(place_names.into_iter().map(|name| (name, index)));
+            places.push(place);
+        }
+        names.sort();
+        info!("Gazetteer loaded, {} places", places.len());
+        Ok(Self { places, names })
+    }
+
+    // Places matching the query, exact names first, then prefixes, then names with typos.
+    pub fn search(&self, query: &str, limit: usize) -> Vec<&Place> {
+        let query = normalize(query);
+        if query.is_empty() {
+            return Vec::new();
+        }
+        // Best rank of every matching place: 0 for an exact name, 1 for a prefix, 2 for a typo.
+        let mut ranks: HashMap<u32, u8> = HashMap::new();
+
+        let start = self
+            .names
+            .partition_point(|(name, _)| name.as_str() < query.as_str());
+        for (name, place) in self.names[start..]
+            .iter()
+            .take_while(|(name, _)| name.starts_with(&query))
+        {
+            add_match(&mut ranks, *place, if *name == query { 0 } else { 1 });
+        }
+
+        // Typos are looked for among the names sharing the first letter, the prefix of each name
+        // as long as the query is compared with it.
+        let length = query.chars().count();
+        if ranks.len() < limit && length >= FUZZY_QUERY_LENGTH {
+            let max_edits = length / FUZZY_QUERY_LENGTH;
+            let first = query.chars().next().unwrap_or_default();
+            let start = self
+                .names
+                .partition_point(|(name, _)| name.chars().next() < Some(first));
+            let query: Vec<char> = query.chars().collect();
+            let mut prefix = Vec::with_capacity(length);
+            let mut rows = (Vec::new(), Vec::new());
+            for (name, place) in self.names[start..]
+                .iter()
+                .take_while(|(name, _)| name.starts_with(first))
+            {
+                prefix.clear();
+                prefix.extend(name.chars().take(length));
+                if within_edit_distance(&query, &prefix, max_edits, &mut rows) {
+                    add_match(&mut ranks, *place, 2);
+                }
+            }
+        }
+
+        let mut matches: Vec<(u8, &Place)> = ranks
+            .into_iter()
+            .map(|(place, rank)| (rank, &self.places[place as usize]))
+            .collect();
+        matches.sort_by(|(a_rank, a), (b_rank, b)| {
+            a_rank
+                .cmp(b_rank)
+                .then(b.population.cmp(&a.population))
+                .then(a.name.cmp(&b.name))
+        });
+        matches
+            .into_iter()
+            .take(limit)
+            .map(|(_, place)| place)
+            .collect()
+    }
+
+    // Closest place to a location, measured on an equirectangular projection.
+    pub fn nearest(&self, lat_lon: LatLon) -> Option<&Place> {
+        let cos_latitude = lat_lon.latitude.to_radians().cos();
+        let distance = |place: &Place| {
+            let longitude = (place.lat_lon.longitude - lat_lon.longitude + 540.0) % 360.0 - 180.0;
+            let latitude = place.lat_lon.latitude - lat_lon.latitude;
+            (longitude * cos_latitude).powi(2) + latitude.powi(2)
+        };
+        self.places
+            .iter()
+            .min_by(|a, b| distance(a).total_cmp(&distance(b)))
+    }
+}
+
+// A GeoNames line and the alternate names of the place.
+fn parse_geonames<'a>(columns: &[&'a str]) -> Option<(Place, Vec<&'a str>)> {
+    let column = |index: usize| columns.get(index).copied();
+    let place = Place {
+        name: column(GEONAMES_NAME)?.to_string(),
+        country: column(GEONAMES_COUNTRY).unwrap_or_default().to_string(),
+        lat_lon: LatLon::new(
+            column(GEONAMES_LATITUDE)?.parse().ok()?,
+            column(GEONAMES_LONGITUDE)?.parse().ok()?,
+        ),
+        population: column(GEONAMES_POPULATION)
+            .and_then(|population| population.parse().ok())
+            .unwrap_or(0),
+    };
+    let alternate_names = column(GEONAMES_ASCII_NAME)
+        .into_iter()
+        .chain(
+            column(GEONAMES_ALTERNATE_NAMES)
+                .unwrap_or_default()
+                .split(','),
+        )
+        .collect();
+    Some((place, alternate_names))
+}
+
+// Column positions of a file with a header.
+struct Header {
+    name: usize,
+    latitude: usize,
+    longitude: usize,
+    population: Option<usize>,
+    country: Option<usize>,
+}
+
+impl Header {
+    fn new(line: &str) -> Result<Self, GazetteerError> {
+        let columns: Vec<&str> = line.split('\t').map(str::trim).collect();
+        let position = |name: &str| columns.iter().position(|column| *column == name);
+        let required =
+            |name: &'static str| position(name).ok_or(GazetteerError::MissingColumn(name));
+        Ok(Self {
+            name: required("name")?,
+            latitude: required("latitude")?,
+            longitude: required("longitude")?,
+            population: position("population"),
+            country: position("country"),
+        })
+    }
+
+    fn parse(&self, columns: &[&str]) -> Option<Place> {
+        let optional = |index: Option<usize>| {
+            index
+                .and_then(|index| columns.get(index))
+                .map(|value| value.trim())
+                .filter(|value| !value.is_empty())
+        };
+        Some(Place {
+            name: columns.get(self.name)?.trim().to_string(),
+            country: optional(self.country).unwrap_or_default().to_string(),
+            lat_lon: LatLon::new(
+                columns.get(self.latitude)?.trim().parse().ok()?,
+                columns.get(self.longitude)?.trim().parse().ok()?,
+            ),
+            population: match optional(self.population) {
+                Some(population) => population.parse().ok()?,
+                None => 0,
+            },
+        })
+    }
+}
+
+fn add_match(ranks: &mut HashMap<u32, u8>, place: u32, rank: u8) {
+    let best = ranks.entry(place).or_insert(rank);
+    *best = (*best).min(rank);
+}
+
+fn normalize(name: &str) -> String {
+    name.trim().to_lowercase()
+}
+
+// Whether the Levenshtein distance of the words is at most `max_edits`. The rows of the distance
+// matrix are reused across calls, and the comparison stops as soon as a row exceeds `max_edits`,
+// which is after a few letters for most names.
+fn within_edit_distance(
+    a: &[char],
+    b: &[char],
+    max_edits: usize,
+    (previous, current): &mut (Vec<usize>, Vec<usize>),
+) -> bool {
+    if a.len().abs_diff(b.len()) > max_edits {
+        return false;
+    }
+    previous.clear();
+    previous.extend(0..=b.len());
+    current.clear();
+    current.resize(b.len() + 1, 0);
+    for (i, a) in a.iter().enumerate() {
+        current[0] = i + 1;
+        for (j, b) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a != b);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        if current.iter().all(|&distance| distance > max_edits) {
+            return false;
+        }
+        std::mem::swap(previous, current);
+    }
+    previous[b.len()] <= max_edits
+}
+
+// Zoom level showing a place, closer for smaller places.
+pub fn place_zoom_level(place: &Place) -> u8 {
+    match place.population {
+        1_000_000.. => 10,
+        100_000.. => 11,
+        10_000.. => 12,
+        _ => 13,
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct Gazetteer {
+    pub index: Option<Arc<PlaceIndex>>,
+    loading: Option<Task<Result<PlaceIndex, GazetteerError>>>,
+}
+
+// Starts reading the configured gazetteer file on the IO task pool, large files take a while.
+pub fn load_gazetteer(mut commands: Commands, config: Res<MapConfig>) {
+    let loading = config
+        .gazetteer
+        .clone()
+        .map(|path| IoTaskPool::get().spawn(async move { PlaceIndex::load(Path::new(&path)) }));
+    commands.insert_resource(Gazetteer {
+        index: None,
+        loading,
+    });
+}
+
+pub fn poll_gazetteer(mut gazetteer: ResMut<Gazetteer>) {
+    // Polling does not count as a change, the go-to box searches again once the index is set.
+    let Some(task) = &mut gazetteer.bypass_change_detection().loading else {
+        return;
+    };
+    let Some(result) = block_on(future::poll_once(task)) else {
+        return;
+    };
+    gazetteer.loading = None;
+    match result {
+        Ok(index) => gazetteer.index = Some(Arc::new(index)),
+        Err(err) => warn!("Cannot load the gazetteer: {}", err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chars(word: &str) -> Vec<char> {
+        word.chars().collect()
+    }
+
+    #[test]
+    fn edit_distance_stops_beyond_the_bound() {
+        let mut rows = (Vec::new(), Vec::new());
+        let mut within = |a: &str, b: &str, max_edits| {
+            within_edit_distance(&chars(a), &chars(b), max_edits, &mut rows)
+        };
+        assert!(within("helsinki", "helsinki", 0));
+        assert!(within("helsinki", "helsinky", 1));
+        assert!(!within("helsinki", "helsinky", 0));
+        assert!(within("helsinki", "hlesinki", 2));
+        assert!(!within("helsinki", "hlesinki", 1));
+        assert!(within("pari", "paris", 1));
+        assert!(!within("pari", "parisi", 1));
+        assert!(!within("helsinki", "hamburg", 2));
+    }
+
+    #[test]
+    fn search_ranks_exact_prefix_and_typo_matches() {
+        let index = PlaceIndex::parse(
+            "name\tlatitude\tlongitude\tpopulation
+Paris\t48.8566\t2.3522\t2100000
+Paris\t33.6609\t-95.5555\t25000
+Parisot\t44.2650\t1.8600\t500
+Parma\t44.8015\t10.3279\t190000
+",
+        )
+        .unwrap();
+        let names = |query: &str| -> Vec<(String, u64)> {
+            index
+                .search(query, 10)
+                .into_iter()
+                .map(|place| (place.name.clone(), place.population))
+                .collect()
+        };
+        assert_eq!(
+            names("paris"),
+            [
+                ("Paris".into(), 2100000),
+                ("Paris".into(), 25000),
+                ("Parisot".into(), 500)
+            ]
+        );
+        // One typo in a query of four letters.
+        assert_eq!(names("parm")[0], ("Parma".into(), 190000));
+        assert!(names("pirma").iter().any(|(name, _)| name == "Parma"));
+        assert!(names("xyz").is_empty());
+    }
+}
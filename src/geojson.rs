@@ -0,0 +1,872 @@
+// GeoJSON vector data drawn over the tiles. The files listed in `geojson` are read at startup on
+// the compute task pool, projected to Web Mercator world coordinates and drawn as meshes above the
+// overlays: polygons are filled and outlined, lines are stroked and points are drawn as dots.
+//
+// Each feature is styled by its properties, following the simplestyle spec:
+// - `stroke`, `stroke-width` (or `width`, in pixels) and `stroke-opacity` for lines and outlines,
+// - `fill` and `fill-opacity` for polygons and points, `marker-color` overriding the point color,
+// - `opacity` multiplying both opacities.
+//
+// Strokes keep their width in pixels: their meshes are rebuilt when the zoom changes enough.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use bevy::math::DVec2;
+use bevy::prelude::*;
+use bevy::render::mesh::{Indices, PrimitiveTopology};
+use bevy::render::render_asset::RenderAssetUsages;
+use bevy::sprite::{MaterialMesh2dBundle, Mesh2dHandle};
+use bevy::tasks::{block_on, futures_lite::future, AsyncComputeTaskPool, Task};
+use serde_json::Value;
+
+use crate::config::MapConfig;
+use crate::projection::{lat_lon_to_meters, LatLon, WorldOrigin};
+use crate::MainCamera;
+
+// Vector data is drawn above the overlays, fills below strokes below points.
+const VECTOR_Z: f32 = 500.0;
+const DEFAULT_COLOR: Srgba = Srgba::rgb(0.333, 0.333, 0.333);
+const DEFAULT_STROKE_WIDTH: f32 = 2.0;
+const DEFAULT_FILL_OPACITY: f32 = 0.6;
+// Radius of the points, in pixels.
+const POINT_RADIUS: f32 = 5.0;
+// Longest miter at line joins, in stroke widths, sharper joins are cut.
+const MITER_LIMIT: f32 = 2.0;
+// Relative change of the projection scale before the strokes are rebuilt.
+const STROKE_REBUILD_TOLERANCE: f32 = 0.05;
+
+#[derive(Debug)]
+pub enum GeoJsonError {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, serde_json::Error),
+    Invalid(PathBuf, String),
+}
+
+impl fmt::Display for GeoJsonError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GeoJsonError::Read(path, err) => write!(f, "cannot read {}: {}", path.display(), err),
+            GeoJsonError::Parse(path, err) => {
+                write!(f, "invalid JSON in {}: {}", path.display(), err)
+            }
+            GeoJsonError::Invalid(path, message) => {
+                write!(f, "invalid GeoJSON in {}: {}", path.display(), message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for GeoJsonError {}
+
+#[derive(Clone, Copy, Debug)]
+struct VectorStyle {
+    stroke: Color,
+    stroke_width: f32,
+    fill: Color,
+    point: Color,
+}
+
+impl VectorStyle {
+    fn from_properties(properties: Option<&Value>) -> Self {
+        let property = |name: &str| properties.and_then(|properties| properties.get(name));
+        let number = |name: &str| {
+            property(name)
+                .and_then(Value::as_f64)
+                .map(|value| value as f32)
+        };
+        let color = |name: &str| {
+            property(name)
+                .and_then(Value::as_str)
+                .and_then(|hex| Srgba::hex(hex).ok())
+        };
+        let opacity = number("opacity").unwrap_or(1.0);
+        let fill_opacity = number("fill-opacity").unwrap_or(DEFAULT_FILL_OPACITY) * opacity;
+        let fill = color("fill").unwrap_or(DEFAULT_COLOR);
+        Self {
+            stroke: color("stroke")
+                .unwrap_or(DEFAULT_COLOR)
+                .with_alpha(number("stroke-opacity").unwrap_or(1.0) * opacity)
+                .into(),
+            stroke_width: number("stroke-width")
+                .or_else(|| number("width"))
+                .unwrap_or(DEFAULT_STROKE_WIDTH),
+            fill: fill.with_alpha(fill_opacity).into(),
+            point: color("marker-color")
+                .unwrap_or(fill)
+                .with_alpha(fill_opacity)
+                .into(),
+        }
+    }
+}
+
+// Geometries in projected meters, the multi geometries split into their parts.
+#[derive(Default)]
+struct Shapes {
+    points: Vec<DVec2>,
+    lines: Vec<Vec<DVec2>>,
+    // Outer ring then holes.
+    polygons: Vec<Vec<Vec<DVec2>>>,
+}
+
+struct VectorFeature {
+    shapes: Shapes,
+    style: VectorStyle,
+}
+
+fn load_features(path: &Path) -> Result<Vec<VectorFeature>, GeoJsonError> {
+    let text =
+        std::fs::read_to_string(path).map_err(|err| GeoJsonError::Read(path.to_path_buf(), err))?;
+    let value: Value =
+        serde_json::from_str(&text).map_err(|err| GeoJsonError::Parse(path.to_path_buf(), err))?;
+    let mut features = Vec::new();
+    parse_object(&value, &mut features)
+        .map_err(|message| GeoJsonError::Invalid(path.to_path_buf(), message))?;
+    Ok(features)
+}
+
+// A feature collection, a feature or a bare geometry, drawn with the default style.
+fn parse_object(value: &Value, features: &mut Vec<VectorFeature>) -> Result<(), String> {
+    match value.get("type").and_then(Value::as_str) {
+        Some("FeatureCollection") => {
+            let members = value
+                .get("features")
+                .and_then(Value::as_array)
+                .ok_or("feature collection without features")?;
+            for member in members {
+                parse_object(member, features)?;
+            }
+        }
+        Some("Feature") => {
+            let mut shapes = Shapes::default();
+            // Features without location have a null geometry.
+            if let Some(geometry) = value.get("geometry").filter(|geometry| !geometry.is_null()) {
+                parse_geometry(geometry, &mut shapes)?;
+            }
+            features.push(VectorFeature {
+                shapes,
+                style: VectorStyle::from_properties(value.get("properties")),
+            });
+        }
+        Some(_) => {
+            let mut shapes = Shapes::default();
+            parse_geometry(value, &mut shapes)?;
+            features.push(VectorFeature {
+                shapes,
+                style: VectorStyle::from_properties(None),
+            });
+        }
+        None => return Err("object without type".into()),
+    }
+    Ok(())
+}
+
+fn parse_geometry(value: &Value, shapes: &mut Shapes) -> Result<(), String> {
+    let kind = value
+        .get("type")
+        .and_then(Value::as_str)
+        .ok_or("geometry without type")?;
+    if kind == "GeometryCollection" {
+        let geometries = value
+            .get("geometries")
+            .and_then(Value::as_array)
+            .ok_or("geometry collection without geometries")?;
+        for geometry in geometries {
+            parse_geometry(geometry, shapes)?;
+        }
+        return Ok(());
+    }
+
+    let coordinates = value
+        .get("coordinates")
+        .ok_or_else(|| format!("{} without coordinates", kind))?;
+    match kind {
+        "Point" => shapes.points.push(parse_position(coordinates)?),
+        "MultiPoint" => shapes.points.extend(parse_positions(coordinates)?),
+        "LineString" => shapes.lines.push(parse_positions(coordinates)?),
+        "MultiLineString" => shapes.lines.extend(parse_rings(coordinates)?),
+        "Polygon" => shapes.polygons.push(parse_rings(coordinates)?),
+        "MultiPolygon" => {
+            for polygon in parse_array(coordinates)? {
+                shapes.polygons.push(parse_rings(polygon)?);
+            }
+        }
+        _ => return Err(format!("unknown geometry type {}", kind)),
+    }
+    Ok(())
+}
+
+fn parse_array(value: &Value) -> Result<&[Value], String> {
+    value
+        .as_array()
+        .map(Vec::as_slice)
+        .ok_or_else(|| format!("invalid coordinates {}", value))
+}
+
+fn parse_positions(value: &Value) -> Result<Vec<DVec2>, String> {
+    parse_array(value)?.iter().map(parse_position).collect()
+}
+
+fn parse_rings(value: &Value) -> Result<Vec<Vec<DVec2>>, String> {
+    parse_array(value)?.iter().map(parse_positions).collect()
+}
+
+// `[longitude, latitude]`, an altitude is ignored.
+fn parse_position(value: &Value) -> Result<DVec2, String> {
+    match value.as_array().map(Vec::as_slice) {
+        Some([longitude, latitude, ..]) => match (longitude.as_f64(), latitude.as_f64()) {
+            (Some(longitude), Some(latitude)) => {
+                Ok(lat_lon_to_meters(LatLon::new(latitude, longitude)))
+            }
+            _ => Err(format!("invalid position {}", value)),
+        },
+        _ => Err(format!("invalid position {}", value)),
+    }
+}
+
+// Strokes of a feature, in coordinates relative to the entity and with their closed flag, the mesh
+// is rebuilt from them when the zoom changes.
+#[derive(Component)]
+pub struct VectorStroke {
+    lines: Vec<(Vec<Vec2>, bool)>,
+    width: f32,
+}
+
+#[derive(Component)]
+pub struct VectorPoint;
+
+// Projection scales the strokes and the points were last sized for, and the features still being
+// prepared.
+#[derive(Resource, Default)]
+pub struct VectorLayer {
+    stroke_scale: f32,
+    point_scale: f32,
+    loading: Option<Task<Vec<PreparedFeature>>>,
+}
+
+// A feature ready to be spawned: its fill triangulated and its strokes and points around an anchor.
+struct PreparedFeature {
+    // First position of the feature, in projected meters, meshes are built around it to keep the
+    // f32 coordinates small.
+    anchor: DVec2,
+    fill: (Vec<Vec2>, Vec<u32>),
+    strokes: Vec<(Vec<Vec2>, bool)>,
+    points: Vec<DVec2>,
+    style: VectorStyle,
+}
+
+impl PreparedFeature {
+    fn new(feature: VectorFeature) -> Option<Self> {
+        let Shapes {
+            points,
+            lines,
+            polygons,
+        } = feature.shapes;
+        let anchor = points
+            .first()
+            .or_else(|| lines.iter().flatten().next())
+            .or_else(|| polygons.iter().flatten().flatten().next())
+            .copied()?;
+        let relative = |ring: &Vec<DVec2>| -> Vec<Vec2> {
+            ring.iter()
+                .map(|point| (*point - anchor).as_vec2())
+                .collect()
+        };
+
+        let (mut positions, mut indices) = (Vec::new(), Vec::new());
+        for polygon in &polygons {
+            let rings: Vec<Vec<Vec2>> = polygon.iter().map(relative).collect();
+            let (vertices, triangles) = triangulate(&rings);
+            let start = positions.len() as u32;
+            positions.extend(vertices);
+            indices.extend(triangles.into_iter().map(|index| start + index));
+        }
+        let strokes = lines
+            .iter()
+            .map(|line| (relative(line), false))
+            .chain(polygons.iter().flatten().map(|ring| (relative(ring), true)))
+            .collect();
+        Some(Self {
+            anchor,
+            fill: (positions, indices),
+            strokes,
+            points,
+            style: feature.style,
+        })
+    }
+}
+
+// Reads and triangulates the configured files on the compute task pool, large polygons take a
+// while, the features are drawn once ready.
+pub fn setup_vector_layer(mut commands: Commands, config: Res<MapConfig>) {
+    let paths = config.geojson.clone();
+    let loading = (!paths.is_empty()).then(|| {
+        AsyncComputeTaskPool::get().spawn(async move {
+            let mut features = Vec::new();
+            for path in &paths {
+                match load_features(Path::new(path)) {
+                    Ok(loaded) => {
+                        info!("Loaded {} features from {}", loaded.len(), path);
+                        features.extend(loaded);
+                    }
+                    Err(err) => warn!("Cannot load the vector data: {}", err),
+                }
+            }
+            features
+                .into_iter()
+                .filter_map(PreparedFeature::new)
+                .collect()
+        })
+    });
+    commands.insert_resource(VectorLayer {
+        loading,
+        ..default()
+    });
+}
+
+fn spawn_features(
+    commands: &mut Commands,
+    origin: &WorldOrigin,
+    meshes: &mut Assets<Mesh>,
+    materials: &mut Assets<ColorMaterial>,
+    features: Vec<PreparedFeature>,
+) {
+    // Later features are drawn over earlier ones.
+    let step = 1.0 / features.len() as f32;
+    let point_mesh = meshes.add(Circle::new(1.0));
+    for (index, feature) in features.into_iter().enumerate() {
+        let z = VECTOR_Z + index as f32 * step;
+        let translation = origin.meters_to_world(feature.anchor);
+        let (positions, indices) = feature.fill;
+        if !indices.is_empty() {
+            commands.spawn(MaterialMesh2dBundle {
+                mesh: meshes.add(triangle_mesh(&positions, indices)).into(),
+                material: materials.add(feature.style.fill),
+                transform: Transform::from_translation(translation.extend(z)),
+                ..default()
+            });
+        }
+
+        if !feature.strokes.is_empty() && feature.style.stroke_width > 0.0 {
+            // The mesh is built once the projection scale is known, see `update_vector_layer`.
+            commands.spawn((
+                VectorStroke {
+                    lines: feature.strokes,
+                    width: feature.style.stroke_width,
+                },
+                MaterialMesh2dBundle {
+                    material: materials.add(feature.style.stroke),
+                    transform: Transform::from_translation(translation.extend(z + 1.0)),
+                    ..default()
+                },
+            ));
+        }
+
+        let point_material = materials.add(feature.style.point);
+        for point in feature.points {
+            commands.spawn((
+                VectorPoint,
+                MaterialMesh2dBundle {
+                    mesh: point_mesh.clone().into(),
+                    material: point_material.clone(),
+                    transform: Transform::from_translation(
+                        origin.meters_to_world(point).extend(z + 2.0),
+                    ),
+                    ..default()
+                },
+            ));
+        }
+    }
+}
+
+// Draws the features once prepared, and keeps the strokes and the points the same size in pixels
+// as the map zooms.
+#[allow(clippy::too_many_arguments)]
+pub fn update_vector_layer(
+    mut commands: Commands,
+    cameras: Query<&OrthographicProjection, With<MainCamera>>,
+    origin: Res<WorldOrigin>,
+    mut layer: ResMut<VectorLayer>,
+    mut strokes: Query<(&VectorStroke, &mut Mesh2dHandle)>,
+    mut points: Query<&mut Transform, With<VectorPoint>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+) {
+    let loaded = layer
+        .loading
+        .as_mut()
+        .and_then(|task| block_on(future::poll_once(task)));
+    if let Some(features) = loaded {
+        layer.loading = None;
+        spawn_features(
+            &mut commands,
+            &origin,
+            &mut meshes,
+            &mut materials,
+            features,
+        );
+        // The new strokes and points are sized next frame, once spawned.
+        layer.stroke_scale = 0.0;
+        layer.point_scale = 0.0;
+        return;
+    }
+
+    let scale = cameras.single().scale;
+    if scale != layer.point_scale {
+        layer.point_scale = scale;
+        for mut transform in &mut points {
+            transform.scale = Vec3::splat(POINT_RADIUS * scale);
+        }
+    }
+    if (scale / layer.stroke_scale - 1.0).abs() <= STROKE_REBUILD_TOLERANCE {
+        return;
+    }
+    layer.stroke_scale = scale;
+    // The previous meshes are freed with their handles, degenerate lines get none.
+    for (stroke, mut mesh) in &mut strokes {
+        let (mut positions, mut indices) = (Vec::new(), Vec::new());
+        for (line, closed) in &stroke.lines {
+            extrude_line(
+                line,
+                *closed,
+                stroke.width * scale,
+                &mut positions,
+                &mut indices,
+            );
+        }
+        *mesh = if indices.is_empty() {
+            Mesh2dHandle::default()
+        } else {
+            meshes.add(triangle_mesh(&positions, indices)).into()
+        };
+    }
+}
+
+fn triangle_mesh(positions: &[Vec2], indices: Vec<u32>) -> Mesh {
+    let positions: Vec<[f32; 3]> = positions
+        .iter()
+        .map(|point| [point.x, point.y, 0.0])
+        .collect();
+    Mesh::new(
+        PrimitiveTopology::TriangleList,
+        RenderAssetUsages::RENDER_WORLD,
+    )
+    .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, positions)
+    .with_inserted_indices(Indices::U32(indices))
+}
+
+// Triangles covering a line of the given width, with mitered joins.
+fn extrude_line(
+    line: &[Vec2],
+    closed: bool,
+    width: f32,
+    positions: &mut Vec<Vec2>,
+    indices: &mut Vec<u32>,
+) {
+    let mut points: Vec<Vec2> = Vec::with_capacity(line.len());
+    for point in line {
+        if points.last() != Some(point) {
+            points.push(*point);
+        }
+    }
+    // GeoJSON rings repeat their first position at the end.
+    if closed && points.len() > 1 && points.first() == points.last() {
+        points.pop();
+    }
+    let count = points.len();
+    if count < 2 {
+        return;
+    }
+
+    let half_width = width / 2.0;
+    let normal = |from: Vec2, to: Vec2| (to - from).normalize().perp();
+    let start = positions.len() as u32;
+    for (index, point) in points.iter().enumerate() {
+        let previous = (index > 0 || closed).then(|| points[(index + count - 1) % count]);
+        let next = (index + 1 < count || closed).then(|| points[(index + 1) % count]);
+        let offset = match (previous, next) {
+            (Some(previous), Some(next)) => {
+                let (incoming, outgoing) = (normal(previous, *point), normal(*point, next));
+                let miter = (incoming + outgoing).normalize_or_zero();
+                let cos = miter.dot(outgoing);
+                if cos > 1.0 / MITER_LIMIT {
+                    miter * half_width / cos
+                } else {
+                    outgoing * half_width
+                }
+            }
+            (Some(previous), None) => normal(previous, *point) * half_width,
+            (None, Some(next)) => normal(*point, next) * half_width,
+            (None, None) => Vec2::ZERO,
+        };
+        positions.extend([*point + offset, *point - offset]);
+    }
+    let segments = if closed { count } else { count - 1 };
+    for segment in 0..segments {
+        let a = start + 2 * segment as u32;
+        let b = start + 2 * ((segment + 1) % count) as u32;
+        indices.extend([a, a + 1, b, b, a + 1, b + 1]);
+    }
+}
+
+// Triangles of a polygon given by its outer ring and holes: the holes are joined to the outer ring
+// by bridges into a single ring, which is cut into triangles by clipping its ears.
+// Returns the vertices and the indices of the triangles in them.
+fn triangulate(rings: &[Vec<Vec2>]) -> (Vec<Vec2>, Vec<u32>) {
+    let mut rings = rings.iter().map(|ring| clean_ring(ring));
+    let Some(mut outer) = rings.next().filter(|ring| ring.len() >= 3) else {
+        return (Vec::new(), Vec::new());
+    };
+    if signed_area(&outer) < 0.0 {
+        outer.reverse();
+    }
+    let mut holes: Vec<Vec<Vec2>> = rings.filter(|ring| ring.len() >= 3).collect();
+    for hole in &mut holes {
+        if signed_area(hole) > 0.0 {
+            hole.reverse();
+        }
+    }
+
+    // Holes are bridged from their rightmost vertex, the rightmost hole first.
+    let rightmost = |ring: &[Vec2]| {
+        (0..ring.len())
+            .max_by(|a, b| ring[*a].x.total_cmp(&ring[*b].x))
+            .unwrap_or(0)
+    };
+    holes.sort_by(|a, b| b[rightmost(b)].x.total_cmp(&a[rightmost(a)].x));
+    for (index, hole) in holes.iter().enumerate() {
+        let start = rightmost(hole);
+        let from = hole[start];
+        let crosses = |to: Vec2| {
+            let ring_crosses = |ring: &[Vec2]| {
+                (0..ring.len())
+                    .any(|i| segments_cross(from, to, ring[i], ring[(i + 1) % ring.len()]))
+            };
+            ring_crosses(&outer) || holes[index..].iter().any(|hole| ring_crosses(hole))
+        };
+        // The closest vertex of the outer ring the bridge reaches without crossing an edge.
+        let Some(bridge) = (0..outer.len())
+            .filter(|&vertex| !crosses(outer[vertex]))
+            .min_by(|a, b| {
+                outer[*a]
+                    .distance_squared(from)
+                    .total_cmp(&outer[*b].distance_squared(from))
+            })
+        else {
+            continue;
+        };
+        let mut merged = Vec::with_capacity(outer.len() + hole.len() + 2);
+        merged.extend_from_slice(&outer[..=bridge]);
+        merged.extend(hole[start..].iter().chain(&hole[..=start]));
+        merged.extend_from_slice(&outer[bridge..]);
+        outer = merged;
+    }
+
+    // Ears are clipped from a linked list of the remaining vertices. Only the reflex vertices can be
+    // inside an ear, so only they are tested against every candidate.
+    let count = outer.len();
+    let mut previous: Vec<usize> = (0..count)
+        .map(|vertex| (vertex + count - 1) % count)
+        .collect();
+    let mut next: Vec<usize> = (0..count).map(|vertex| (vertex + 1) % count).collect();
+    let is_reflex = |a: usize, b: usize, c: usize| cross(outer[a], outer[b], outer[c]) <= 0.0;
+    let mut reflex: Vec<usize> = (0..count)
+        .filter(|&vertex| is_reflex(previous[vertex], vertex, next[vertex]))
+        .collect();
+    let mut triangles = Vec::with_capacity(3 * count);
+    let mut remaining = count;
+    let mut vertex = 0;
+    // Vertices tried since the last ear was clipped, a full turn without ear ends the clipping.
+    let mut tried = 0;
+    while remaining > 3 && tried < remaining {
+        let (a, c) = (previous[vertex], next[vertex]);
+        if is_ear(&outer, &reflex, a, vertex, c) {
+            triangles.extend([a, vertex, c].map(|index| index as u32));
+            next[a] = c;
+            previous[c] = a;
+            remaining -= 1;
+            // Clipping the ear can make its neighbours convex.
+            reflex.retain(|&other| {
+                (other != a && other != c) || is_reflex(previous[other], other, next[other])
+            });
+            vertex = c;
+            tried = 0;
+        } else {
+            vertex = next[vertex];
+            tried += 1;
+        }
+    }
+    if remaining == 3 {
+        triangles.extend([previous[vertex], vertex, next[vertex]].map(|index| index as u32));
+    }
+    (outer, triangles)
+}
+
+// Ring without its closing position or repeated positions.
+fn clean_ring(ring: &[Vec2]) -> Vec<Vec2> {
+    let mut cleaned: Vec<Vec2> = Vec::with_capacity(ring.len());
+    for point in ring {
+        if cleaned.last() != Some(point) {
+            cleaned.push(*point);
+        }
+    }
+    if cleaned.len() > 1 && cleaned.first() == cleaned.last() {
+        cleaned.pop();
+    }
+    cleaned
+}
+
+// Positive for counterclockwise rings.
+fn signed_area(ring: &[Vec2]) -> f32 {
+    (0..ring.len())
+        .map(|i| ring[i].perp_dot(ring[(i + 1) % ring.len()]))
+        .sum::<f32>()
+        / 2.0
+}
+
+fn cross(a: Vec2, b: Vec2, c: Vec2) -> f32 {
+    (b - a).perp_dot(c - a)
+}
+
+// Whether the segments cross at a point inside both, touching ends do not count.
+fn segments_cross(a: Vec2, b: Vec2, c: Vec2, d: Vec2) -> bool {
+    let (d1, d2) = (cross(a, b, c), cross(a, b, d));
+    let (d3, d4) = (cross(c, d, a), cross(c, d, b));
+    d1 * d2 < 0.0 && d3 * d4 < 0.0
+}
+
+// Whether the corner at `b` of a counterclockwise ring is convex and holds none of its reflex
+// vertices.
+fn is_ear(vertices: &[Vec2], reflex: &[usize], a: usize, b: usize, c: usize) -> bool {
+    let [pa, pb, pc] = [a, b, c].map(|index| vertices[index]);
+    if cross(pa, pb, pc) <= 0.0 {
+        return false;
+    }
+    reflex.iter().all(|&index| {
+        let point = vertices[index];
+        // Bridges duplicate vertices, a copy of a corner is not inside.
+        if point == pa || point == pb || point == pc {
+            return true;
+        }
+        cross(pa, pb, point) < 0.0 || cross(pb, pc, point) < 0.0 || cross(pc, pa, point) < 0.0
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn ring(points: &[(f32, f32)]) -> Vec<Vec2> {
+        points.iter().map(|&(x, y)| Vec2::new(x, y)).collect()
+    }
+
+    // Area covered by the triangles, which must all be counterclockwise.
+    fn triangulated_area(rings: &[Vec<Vec2>]) -> f32 {
+        let (vertices, triangles) = triangulate(rings);
+        triangles
+            .chunks(3)
+            .map(|triangle| {
+                let [a, b, c] = [0, 1, 2].map(|corner| vertices[triangle[corner] as usize]);
+                let area = cross(a, b, c) / 2.0;
+                assert!(area >= 0.0, "clockwise triangle {:?}", triangle);
+                area
+            })
+            .sum()
+    }
+
+    #[test]
+    fn triangulate_covers_simple_polygons() {
+        let square = ring(&[(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0), (0.0, 0.0)]);
+        assert_eq!(triangulated_area(std::slice::from_ref(&square)), 1.0);
+        // Clockwise rings are reversed.
+        let clockwise: Vec<Vec2> = square.into_iter().rev().collect();
+        assert_eq!(triangulated_area(&[clockwise]), 1.0);
+
+        let concave = ring(&[
+            (0.0, 0.0),
+            (2.0, 0.0),
+            (2.0, 1.0),
+            (1.0, 1.0),
+            (1.0, 2.0),
+            (0.0, 2.0),
+        ]);
+        assert_eq!(triangulated_area(std::slice::from_ref(&concave)), 3.0);
+        assert_eq!(triangulate(&[concave]).1.len(), 4 * 3);
+
+        // Too few positions once the closing one is removed.
+        let degenerate = ring(&[(0.0, 0.0), (1.0, 0.0), (0.0, 0.0)]);
+        assert_eq!(triangulate(&[degenerate]), (Vec::new(), Vec::new()));
+        assert_eq!(triangulate(&[]), (Vec::new(), Vec::new()));
+    }
+
+    #[test]
+    fn triangulate_leaves_the_holes_out() {
+        let outer = ring(&[(0.0, 0.0), (4.0, 0.0), (4.0, 4.0), (0.0, 4.0)]);
+        let hole = ring(&[(1.0, 1.0), (1.0, 3.0), (3.0, 3.0), (3.0, 1.0)]);
+        let second_hole = ring(&[(3.5, 0.5), (3.5, 1.0), (3.8, 1.0), (3.8, 0.5)]);
+        assert_eq!(triangulated_area(&[outer.clone(), hole.clone()]), 12.0);
+        let area = triangulated_area(&[outer, hole, second_hole]);
+        assert!((area - 11.85).abs() < 1e-4, "area {}", area);
+    }
+
+    #[test]
+    fn triangulate_large_rings() {
+        // A star with many spikes, every other vertex reflex.
+        let count = 4000;
+        let star: Vec<Vec2> = (0..count)
+            .map(|index| {
+                let angle = index as f32 / count as f32 * std::f32::consts::TAU;
+                let radius = if index % 2 == 0 { 1000.0 } else { 990.0 };
+                Vec2::from_angle(angle) * radius
+            })
+            .collect();
+        let area = triangulated_area(&[star]);
+        let expected = std::f32::consts::PI * 995.0 * 995.0;
+        assert!((area / expected - 1.0).abs() < 0.01, "area {}", area);
+    }
+
+    #[test]
+    fn extrude_line_offsets_by_half_the_width() {
+        let (mut positions, mut indices) = (Vec::new(), Vec::new());
+        extrude_line(
+            &ring(&[(0.0, 0.0), (10.0, 0.0), (10.0, 0.0), (10.0, 10.0)]),
+            false,
+            2.0,
+            &mut positions,
+            &mut indices,
+        );
+        // Repeated positions are skipped, the corner is mitered.
+        assert_eq!(
+            positions,
+            ring(&[
+                (0.0, 1.0),
+                (0.0, -1.0),
+                (9.0, 1.0),
+                (11.0, -1.0),
+                (9.0, 10.0),
+                (11.0, 10.0)
+            ])
+        );
+        assert_eq!(indices, [0, 1, 2, 2, 1, 3, 2, 3, 4, 4, 3, 5]);
+    }
+
+    #[test]
+    fn extrude_line_closes_rings_and_skips_degenerate_lines() {
+        let (mut positions, mut indices) = (Vec::new(), Vec::new());
+        let square = ring(&[(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0), (0.0, 0.0)]);
+        extrude_line(&square, true, 0.5, &mut positions, &mut indices);
+        assert_eq!(positions.len(), 8);
+        assert_eq!(indices.len(), 4 * 6);
+        // The last segment joins the first vertices.
+        assert_eq!(indices[18..], [6, 7, 0, 0, 7, 1]);
+
+        // Appended after the previous lines.
+        extrude_line(
+            &ring(&[(5.0, 5.0), (5.0, 5.0)]),
+            false,
+            1.0,
+            &mut positions,
+            &mut indices,
+        );
+        assert_eq!((positions.len(), indices.len()), (8, 24));
+        extrude_line(
+            &ring(&[(5.0, 5.0), (6.0, 5.0)]),
+            false,
+            1.0,
+            &mut positions,
+            &mut indices,
+        );
+        assert_eq!(indices[24..], [8, 9, 10, 10, 9, 11]);
+    }
+
+    fn parse(value: Value) -> Result<Vec<VectorFeature>, String> {
+        let mut features = Vec::new();
+        parse_object(&value, &mut features).map(|()| features)
+    }
+
+    #[test]
+    fn parse_every_geometry_type() {
+        let square = json!([[0, 0], [1, 0], [1, 1], [0, 1], [0, 0]]);
+        let features = parse(json!({
+            "type": "FeatureCollection",
+            "features": [
+                {
+                    "type": "Feature",
+                    "properties": { "stroke": "#ff0000", "stroke-width": 4 },
+                    "geometry": { "type": "MultiPoint", "coordinates": [[0, 0], [1, 1, 100]] },
+                },
+                {
+                    "type": "Feature",
+                    "geometry": {
+                        "type": "MultiLineString",
+                        "coordinates": [[[0, 0], [1, 1]], [[2, 2], [3, 3], [4, 4]]],
+                    },
+                },
+                {
+                    "type": "Feature",
+                    "geometry": {
+                        "type": "MultiPolygon",
+                        "coordinates": [[square, [[0.2, 0.2], [0.2, 0.4], [0.4, 0.4], [0.2, 0.2]]], [square]],
+                    },
+                },
+                {
+                    "type": "Feature",
+                    "geometry": {
+                        "type": "GeometryCollection",
+                        "geometries": [
+                            { "type": "Point", "coordinates": [0, 0] },
+                            { "type": "LineString", "coordinates": [[0, 0], [1, 1]] },
+                            { "type": "Polygon", "coordinates": [square] },
+                        ],
+                    },
+                },
+                { "type": "Feature", "properties": null, "geometry": null },
+            ],
+        }))
+        .unwrap();
+        assert_eq!(features.len(), 5);
+        let counts = |shapes: &Shapes| {
+            (
+                shapes.points.len(),
+                shapes.lines.len(),
+                shapes.polygons.len(),
+            )
+        };
+        let counts: Vec<_> = features
+            .iter()
+            .map(|feature| counts(&feature.shapes))
+            .collect();
+        assert_eq!(
+            counts,
+            [(2, 0, 0), (0, 2, 0), (0, 0, 2), (1, 1, 1), (0, 0, 0)]
+        );
+        assert_eq!(features[2].shapes.polygons[0].len(), 2);
+        assert_eq!(features[1].shapes.lines[1].len(), 3);
+
+        // Positions are projected, the altitude is ignored.
+        let point = features[0].shapes.points[1];
+        assert_eq!(point, lat_lon_to_meters(LatLon::new(1.0, 1.0)));
+        assert_eq!(features[0].style.stroke, Color::srgb(1.0, 0.0, 0.0));
+        assert_eq!(features[0].style.stroke_width, 4.0);
+        assert_eq!(features[1].style.stroke_width, DEFAULT_STROKE_WIDTH);
+
+        // Features without geometry are kept but have nothing to draw.
+        assert!(PreparedFeature::new(features.into_iter().last().unwrap()).is_none());
+    }
+
+    #[test]
+    fn parse_bare_geometries_and_errors() {
+        let features = parse(json!({ "type": "Point", "coordinates": [24.9, 60.2] })).unwrap();
+        assert_eq!(features.len(), 1);
+        assert_eq!(features[0].shapes.points.len(), 1);
+
+        assert!(parse(json!({ "coordinates": [0, 0] })).is_err());
+        assert!(parse(json!({ "type": "Circle", "coordinates": [0, 0] })).is_err());
+        assert!(parse(json!({ "type": "Point" })).is_err());
+        assert!(parse(json!({ "type": "Point", "coordinates": [0] })).is_err());
+        assert!(parse(json!({ "type": "LineString", "coordinates": [[0, "a"]] })).is_err());
+        assert!(parse(json!({ "type": "GeometryCollection" })).is_err());
+        assert!(parse(json!({ "type": "FeatureCollection" })).is_err());
+    }
+}
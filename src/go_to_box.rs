@@ -0,0 +1,396 @@
+// Go-to box: `G` opens a text field at the top of the window taking "latitude, longitude[, zoom]"
+// or a place name, Enter flies there and Escape closes it. The other keyboard shortcuts are off
+// while it is open. Place names are searched in the gazetteer as they are typed, the matching
+// places are listed under the field and marked on the map: the arrow keys select one and a click
+// flies to it. The gazetteer is searched on the compute task pool, large ones take a while, Enter
+// pressed meanwhile goes to the first place found. When the gazetteer knows no match, Enter
+// searches the configured geocoder, which is not meant for search as you type.
+
+use bevy::input::keyboard::{Key, KeyboardInput};
+use bevy::input::ButtonState;
+use bevy::prelude::*;
+use bevy::tasks::{block_on, futures_lite::future, AsyncComputeTaskPool, Task};
+
+use crate::fly_to::{parse_location, FlyTo};
+use crate::gazetteer::{place_zoom_level, Gazetteer, PlaceIndex};
+use crate::keyboard::KeyBindings;
+use crate::nominatim::{GeocodedPlace, Geocoder};
+use crate::projection::WorldOrigin;
+use crate::MainCamera;
+
+const PROMPT: &str = "Go to: ";
+const INVALID_LOCATION: &str = "expected latitude, longitude[, zoom] or a place name";
+const NO_PLACE_FOUND: &str = "no place found";
+const SEARCHING: &str = "searching...";
+// Places listed under the field.
+const MAX_RESULTS: usize = 8;
+const RESULT_COLOR: Color = Color::srgba(1.0, 1.0, 1.0, 0.0);
+const SELECTED_RESULT_COLOR: Color = Color::srgba(0.2, 0.4, 0.9, 0.3);
+// Radius in pixels of the markers of the places.
+const MARKER_RADIUS: f32 = 6.0;
+const MARKER_COLOR: Color = Color::srgb(0.2, 0.4, 0.9);
+const SELECTED_MARKER_COLOR: Color = Color::srgb(0.9, 0.2, 0.2);
+
+#[derive(Resource, Default)]
+pub struct GoToBox {
+    pub open: bool,
+    pub text: String,
+    // Shown after the text: why it is not a location, or the state of the geocoder search.
+    status: Option<String>,
+    // Labels and locations of the places matching the text, and the one Enter flies to.
+    results: Vec<(String, FlyTo)>,
+    selected: usize,
+    // Text the results were searched for.
+    searched: String,
+    // Gazetteer search of the text, replaced when the text changes.
+    matching: Option<Task<Vec<(String, FlyTo)>>>,
+    // Whether Enter was pressed for a place, it goes there once the places of the text are found.
+    submitted: bool,
+    // Geocoder search of the text, dropped when the text changes.
+    searching: Option<Task<Result<Vec<GeocodedPlace>, String>>>,
+    // Location flown to, marked until the box opens again.
+    destination: Option<FlyTo>,
+}
+
+#[derive(Component)]
+pub struct GoToField;
+
+#[derive(Component)]
+pub struct GoToText;
+
+// List of the places matching the text.
+#[derive(Component)]
+pub struct GoToResults;
+
+// Entry of the list, with its index in the results.
+#[derive(Component)]
+pub struct GoToResult(usize);
+
+impl GoToBox {
+    // Flies to the location, which stays marked, and closes the box.
+    fn go_to(&mut self, location: FlyTo, fly_to: &mut EventWriter<FlyTo>) {
+        fly_to.send(location);
+        self.destination = Some(location);
+        self.close();
+    }
+
+    // Closes the box, dropping the searches still running.
+    fn close(&mut self) {
+        self.open = false;
+        self.submitted = false;
+        self.searching = None;
+        self.matching = None;
+    }
+}
+
+// Run condition of the systems reading keyboard shortcuts.
+pub fn go_to_box_closed(go_to: Res<GoToBox>) -> bool {
+    !go_to.open
+}
+
+pub fn setup_go_to_box(mut commands: Commands) {
+    commands.init_resource::<GoToBox>();
+    commands
+        .spawn((
+            GoToField,
+            NodeBundle {
+                style: Style {
+                    position_type: PositionType::Absolute,
+                    top: Val::Px(8.0),
+                    left: Val::Percent(30.0),
+                    width: Val::Percent(40.0),
+                    padding: UiRect::all(Val::Px(6.0)),
+                    flex_direction: FlexDirection::Column,
+                    ..default()
+                },
+                background_color: Color::srgba(1.0, 1.0, 1.0, 0.9).into(),
+                visibility: Visibility::Hidden,
+                ..default()
+            },
+        ))
+        .with_children(|parent| {
+            parent.spawn((
+                GoToText,
+                TextBundle::from_section(
+                    PROMPT,
+                    TextStyle {
+                        font_size: 18.0,
+                        color: Color::BLACK,
+                        ..default()
+                    },
+                ),
+            ));
+            parent.spawn((
+                GoToResults,
+                NodeBundle {
+                    style: Style {
+                        flex_direction: FlexDirection::Column,
+                        ..default()
+                    },
+                    ..default()
+                },
+            ));
+        });
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn update_go_to_box(
+    mut commands: Commands,
+    keys: Res<ButtonInput<KeyCode>>,
+    bindings: Res<KeyBindings>,
+    gazetteer: Res<Gazetteer>,
+    geocoder: Option<Res<Geocoder>>,
+    mut inputs: EventReader<KeyboardInput>,
+    mut go_to: ResMut<GoToBox>,
+    mut fly_to: EventWriter<FlyTo>,
+    mut fields: Query<&mut Visibility, With<GoToField>>,
+    mut texts: Query<&mut Text, With<GoToText>>,
+    result_lists: Query<Entity, With<GoToResults>>,
+    mut results: Query<(&GoToResult, &Interaction, &mut BackgroundColor)>,
+) {
+    if !go_to.open {
+        if keys.any_just_pressed(bindings.go_to.iter().copied()) {
+            go_to.open = true;
+            go_to.text.clear();
+            go_to.status = None;
+            go_to.destination = None;
+            // The key opening the box is not typed into it.
+            inputs.clear();
+            *fields.single_mut() = Visibility::Inherited;
+        } else {
+            return;
+        }
+    }
+
+    for input in inputs.read() {
+        if input.state != ButtonState::Pressed {
+            continue;
+        }
+        match &input.logical_key {
+            Key::Character(characters) => {
+                go_to.text.push_str(characters);
+                go_to.submitted = false;
+            }
+            Key::Space => {
+                go_to.text.push(' ');
+                go_to.submitted = false;
+            }
+            Key::Backspace => {
+                go_to.text.pop();
+                go_to.submitted = false;
+            }
+            Key::ArrowDown if !go_to.results.is_empty() => {
+                go_to.selected = (go_to.selected + 1) % go_to.results.len();
+            }
+            Key::ArrowUp if !go_to.results.is_empty() => {
+                go_to.selected = go_to
+                    .selected
+                    .checked_sub(1)
+                    .unwrap_or(go_to.results.len() - 1);
+            }
+            // Coordinates take precedence over the places, which only match names.
+            Key::Enter => match parse_location(&go_to.text) {
+                Some(location) => go_to.go_to(location, &mut fly_to),
+                None => go_to.submitted = true,
+            },
+            Key::Escape => go_to.close(),
+            _ => {}
+        }
+        if !go_to.open {
+            break;
+        }
+    }
+    let clicked = results
+        .iter()
+        .filter(|(_, interaction, _)| **interaction == Interaction::Pressed)
+        .find_map(|(result, ..)| go_to.results.get(result.0))
+        .map(|(_, place)| *place);
+    if let Some(location) = clicked.filter(|_| go_to.open) {
+        go_to.go_to(location, &mut fly_to);
+    }
+    if !go_to.open {
+        *fields.single_mut() = Visibility::Hidden;
+        return;
+    }
+
+    // The places are searched again when the text changes or the gazetteer is loaded, the list is
+    // rebuilt once they are found or the geocoder answers.
+    let mut rebuild = false;
+    if go_to.text != go_to.searched || gazetteer.is_changed() {
+        if go_to.text != go_to.searched {
+            go_to.searched = go_to.text.clone();
+            go_to.status = None;
+            go_to.searching = None;
+        }
+        // A search still running for the previous text is dropped, which cancels it.
+        go_to.matching = None;
+        match &gazetteer.index {
+            Some(index) => {
+                let index = index.clone();
+                let text = go_to.text.clone();
+                go_to.matching = Some(
+                    AsyncComputeTaskPool::get()
+                        .spawn(async move { matching_places(&index, &text) }),
+                );
+            }
+            None => {
+                go_to.results = Vec::new();
+                rebuild = true;
+            }
+        }
+    }
+    let matched = go_to
+        .matching
+        .as_mut()
+        .and_then(|task| block_on(future::poll_once(task)));
+    if let Some(results) = matched {
+        go_to.matching = None;
+        go_to.results = results;
+        rebuild = true;
+    }
+    let searched = go_to
+        .searching
+        .as_mut()
+        .and_then(|task| block_on(future::poll_once(task)));
+    if let Some(result) = searched {
+        go_to.searching = None;
+        match result {
+            Ok(places) if places.is_empty() => go_to.status = Some(NO_PLACE_FOUND.into()),
+            Ok(places) => {
+                go_to.status = None;
+                go_to.results = places
+                    .into_iter()
+                    .take(MAX_RESULTS)
+                    .map(|place| {
+                        let location = FlyTo {
+                            lat_lon: place.lat_lon,
+                            zoom_level: place.zoom_level,
+                        };
+                        (place.name, location)
+                    })
+                    .collect();
+                rebuild = true;
+            }
+            Err(err) => {
+                warn!("Geocoder search failed: {}", err);
+                go_to.status = Some(format!("search failed: {}", err));
+            }
+        }
+    }
+
+    // Enter goes to the selected place, or the first one when it was pressed during the search.
+    // Without a match the geocoder is searched.
+    if go_to.submitted && go_to.matching.is_none() {
+        go_to.submitted = false;
+        let selected = if rebuild { 0 } else { go_to.selected };
+        match (go_to.results.get(selected), &geocoder) {
+            (Some((_, place)), _) => {
+                let place = *place;
+                go_to.go_to(place, &mut fly_to);
+                *fields.single_mut() = Visibility::Hidden;
+                return;
+            }
+            (None, Some(geocoder)) if !go_to.text.trim().is_empty() => {
+                go_to.searching = Some(geocoder.search(&go_to.text));
+                go_to.status = Some(SEARCHING.into());
+            }
+            (None, _) => go_to.status = Some(INVALID_LOCATION.into()),
+        }
+    }
+
+    if rebuild {
+        go_to.selected = 0;
+        let list = result_lists.single();
+        commands.entity(list).despawn_descendants();
+        commands.entity(list).with_children(|parent| {
+            for (index, (label, _)) in go_to.results.iter().enumerate() {
+                parent
+                    .spawn((
+                        GoToResult(index),
+                        ButtonBundle {
+                            style: Style {
+                                padding: UiRect::axes(Val::Px(4.0), Val::Px(2.0)),
+                                ..default()
+                            },
+                            background_color: RESULT_COLOR.into(),
+                            ..default()
+                        },
+                    ))
+                    .with_children(|parent| {
+                        parent.spawn(TextBundle::from_section(
+                            label.clone(),
+                            TextStyle {
+                                font_size: 16.0,
+                                color: Color::BLACK,
+                                ..default()
+                            },
+                        ));
+                    });
+            }
+        });
+    }
+
+    let mut value = format!("{}{}_", PROMPT, go_to.text);
+    if let Some(status) = &go_to.status {
+        value = format!("{}  ({})", value, status);
+    }
+    let mut text = texts.single_mut();
+    if text.sections[0].value != value {
+        text.sections[0].value = value;
+    }
+
+    for (result, interaction, mut background) in &mut results {
+        let selected = result.0 == go_to.selected || *interaction == Interaction::Hovered;
+        *background = if selected {
+            SELECTED_RESULT_COLOR
+        } else {
+            RESULT_COLOR
+        }
+        .into();
+    }
+}
+
+// Places of the gazetteer matching the text with their labels, none for coordinates.
+fn matching_places(index: &PlaceIndex, text: &str) -> Vec<(String, FlyTo)> {
+    if parse_location(text).is_some() {
+        return Vec::new();
+    }
+    index
+        .search(text, MAX_RESULTS)
+        .into_iter()
+        .map(|place| {
+            let location = FlyTo {
+                lat_lon: place.lat_lon,
+                zoom_level: place_zoom_level(place),
+            };
+            (place.label(), location)
+        })
+        .collect()
+}
+
+// Marks the listed places on the map, the selected one in red, and the place flown to.
+pub fn draw_place_markers(
+    go_to: Res<GoToBox>,
+    origin: Res<WorldOrigin>,
+    cameras: Query<&OrthographicProjection, With<MainCamera>>,
+    mut gizmos: Gizmos,
+) {
+    let radius = MARKER_RADIUS * cameras.single().scale;
+    let mut marker = |location: &FlyTo, color: Color| {
+        let center = origin.lat_lon_to_world(location.lat_lon);
+        gizmos.circle_2d(center, radius, color);
+        gizmos.circle_2d(center, radius / 3.0, color);
+    };
+    if go_to.open {
+        for (index, (_, location)) in go_to.results.iter().enumerate() {
+            if index != go_to.selected {
+                marker(location, MARKER_COLOR);
+            }
+        }
+        if let Some((_, location)) = go_to.results.get(go_to.selected) {
+            marker(location, SELECTED_MARKER_COLOR);
+        }
+    } else if let Some(location) = &go_to.destination {
+        marker(location, SELECTED_MARKER_COLOR);
+    }
+}
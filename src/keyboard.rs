@@ -0,0 +1,120 @@
+// Keyboard navigation: pan the map with the arrows or WASD, zoom through the slippy levels
+// with +/- and jump back to the initial view with Home.
+
+use bevy::prelude::*;
+
+use crate::fly_to::ActiveFlight;
+use crate::projection::{
+    scale_to_zoom_level, zoom_level_to_scale, WorldOrigin, MAX_ZOOM_LEVEL, MIN_ZOOM_LEVEL,
+};
+use crate::{InitialView, MainCamera, PanInertia};
+
+#[derive(Resource)]
+pub struct KeyBindings {
+    pub pan_up: Vec<KeyCode>,
+    pub pan_down: Vec<KeyCode>,
+    pub pan_left: Vec<KeyCode>,
+    pub pan_right: Vec<KeyCode>,
+    pub zoom_in: Vec<KeyCode>,
+    pub zoom_out: Vec<KeyCode>,
+    pub reset: Vec<KeyCode>,
+    pub next_layer: Vec<KeyCode>,
+    // The n-th key toggles the n-th overlay.
+    pub toggle_overlays: Vec<KeyCode>,
+    pub overlay_opacity_down: Vec<KeyCode>,
+    pub overlay_opacity_up: Vec<KeyCode>,
+    // Opens the go-to box.
+    pub go_to: Vec<KeyCode>,
+    // Fraction of the window size moved by a single pan key press.
+    pub pan_fraction: f32,
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        Self {
+            pan_up: vec![KeyCode::ArrowUp, KeyCode::KeyW],
+            pan_down: vec![KeyCode::ArrowDown, KeyCode::KeyS],
+            pan_left: vec![KeyCode::ArrowLeft, KeyCode::KeyA],
+            pan_right: vec![KeyCode::ArrowRight, KeyCode::KeyD],
+            zoom_in: vec![KeyCode::Equal, KeyCode::NumpadAdd],
+            zoom_out: vec![KeyCode::Minus, KeyCode::NumpadSubtract],
+            reset: vec![KeyCode::Home],
+            next_layer: vec![KeyCode::KeyL],
+            toggle_overlays: vec![
+                KeyCode::Digit1,
+                KeyCode::Digit2,
+                KeyCode::Digit3,
+                KeyCode::Digit4,
+                KeyCode::Digit5,
+                KeyCode::Digit6,
+                KeyCode::Digit7,
+                KeyCode::Digit8,
+                KeyCode::Digit9,
+            ],
+            overlay_opacity_down: vec![KeyCode::BracketLeft],
+            overlay_opacity_up: vec![KeyCode::BracketRight],
+            go_to: vec![KeyCode::KeyG],
+            pan_fraction: 0.25,
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn keyboard_navigation(
+    mut cameras: Query<(&mut Transform, &mut OrthographicProjection), With<MainCamera>>,
+    windows: Query<&Window>,
+    keys: Res<ButtonInput<KeyCode>>,
+    bindings: Res<KeyBindings>,
+    initial_view: Res<InitialView>,
+    origin: Res<WorldOrigin>,
+    mut inertia: ResMut<PanInertia>,
+    mut flight: ResMut<ActiveFlight>,
+) {
+    let pressed = |codes: &[KeyCode]| keys.any_just_pressed(codes.iter().copied());
+    let (mut transform, mut projection) = cameras.single_mut();
+
+    if pressed(&bindings.reset) {
+        info!("Resetting the view");
+        let center = origin.meters_to_world(initial_view.center);
+        transform.translation.x = center.x;
+        transform.translation.y = center.y;
+        projection.scale = initial_view.scale;
+        inertia.velocity = Vec2::ZERO;
+        flight.cancel();
+        return;
+    }
+
+    // Pan by a fraction of the visible world.
+    let window = windows.single();
+    let step =
+        Vec2::new(window.width(), window.height()) * projection.scale * bindings.pan_fraction;
+    let mut direction = Vec2::ZERO;
+    if pressed(&bindings.pan_up) {
+        direction.y += 1.0;
+    }
+    if pressed(&bindings.pan_down) {
+        direction.y -= 1.0;
+    }
+    if pressed(&bindings.pan_left) {
+        direction.x -= 1.0;
+    }
+    if pressed(&bindings.pan_right) {
+        direction.x += 1.0;
+    }
+    if direction != Vec2::ZERO {
+        flight.cancel();
+        transform.translation.x += direction.x * step.x;
+        transform.translation.y += direction.y * step.y;
+    }
+
+    // Zoom one slippy level at a time around the viewport center.
+    let zoom_level = scale_to_zoom_level(projection.scale);
+    if pressed(&bindings.zoom_in) && zoom_level < MAX_ZOOM_LEVEL {
+        flight.cancel();
+        projection.scale = zoom_level_to_scale(zoom_level + 1);
+    }
+    if pressed(&bindings.zoom_out) && zoom_level > MIN_ZOOM_LEVEL {
+        flight.cancel();
+        projection.scale = zoom_level_to_scale(zoom_level - 1);
+    }
+}
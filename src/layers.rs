@@ -0,0 +1,185 @@
+// Base layers: one tile source is displayed at a time, switched at runtime with a key or
+// the layer button in the top left corner.
+
+use std::path::PathBuf;
+
+use bevy::prelude::*;
+
+use crate::config::{LayerConfig, MapConfig};
+use crate::keyboard::KeyBindings;
+use crate::offline::OfflineSources;
+use crate::overlays::Overlays;
+use crate::{MapTile, RequestedTiles, TileIndex};
+
+#[derive(Resource)]
+pub struct ActiveBaseLayer {
+    pub index: usize,
+    // Folder under `assets/` the tiles of the layer are downloaded to.
+    pub directory: PathBuf,
+}
+
+impl ActiveBaseLayer {
+    pub fn new(config: &MapConfig, index: usize) -> Self {
+        Self {
+            index,
+            directory: config
+                .layer_directory(&config.base_layers[index].name)
+                .into(),
+        }
+    }
+
+    pub fn layer<'a>(&self, config: &'a MapConfig) -> &'a LayerConfig {
+        &config.base_layers[self.index]
+    }
+}
+
+#[derive(Component)]
+pub struct LayerButton;
+
+#[derive(Component)]
+pub struct LayerLabel;
+
+#[derive(Component)]
+pub struct AttributionText;
+
+pub fn setup_layers(
+    mut commands: Commands,
+    config: Res<MapConfig>,
+    active: Res<ActiveBaseLayer>,
+    overlays: Res<Overlays>,
+    offline: Res<OfflineSources>,
+) {
+    let layer = active.layer(&config);
+
+    // Layer button in the top left corner, cycles through the base layers when clicked.
+    commands
+        .spawn((
+            LayerButton,
+            ButtonBundle {
+                style: Style {
+                    position_type: PositionType::Absolute,
+                    left: Val::Px(8.0),
+                    top: Val::Px(8.0),
+                    padding: UiRect::all(Val::Px(6.0)),
+                    ..default()
+                },
+                background_color: Color::srgba(1.0, 1.0, 1.0, 0.8).into(),
+                ..default()
+            },
+        ))
+        .with_children(|parent| {
+            parent.spawn((
+                LayerLabel,
+                TextBundle::from_section(
+                    layer_label(layer),
+                    TextStyle {
+                        font_size: 18.0,
+                        color: Color::BLACK,
+                        ..default()
+                    },
+                ),
+            ));
+        });
+
+    // Attribution required by the tile server, in the bottom right corner.
+    commands.spawn((
+        AttributionText,
+        TextBundle::from_section(
+            attribution(&config, &active, &overlays, &offline),
+            TextStyle {
+                font_size: 14.0,
+                color: Color::BLACK,
+                ..default()
+            },
+        )
+        .with_background_color(Color::srgba(1.0, 1.0, 1.0, 0.7))
+        .with_style(Style {
+            position_type: PositionType::Absolute,
+            right: Val::Px(4.0),
+            bottom: Val::Px(4.0),
+            ..default()
+        }),
+    ));
+}
+
+// Switches to the next base layer, despawning the tiles of the previous one so the visible
+// tiles are requested again from the new source.
+#[allow(clippy::too_many_arguments)]
+pub fn switch_base_layer(
+    mut commands: Commands,
+    keys: Res<ButtonInput<KeyCode>>,
+    bindings: Res<KeyBindings>,
+    buttons: Query<&Interaction, (Changed<Interaction>, With<LayerButton>)>,
+    config: Res<MapConfig>,
+    mut active: ResMut<ActiveBaseLayer>,
+    tiles: Query<Entity, With<MapTile>>,
+    mut index: ResMut<TileIndex>,
+    mut requested: ResMut<RequestedTiles>,
+    mut labels: Query<&mut Text, With<LayerLabel>>,
+) {
+    let clicked = buttons
+        .iter()
+        .any(|interaction| *interaction == Interaction::Pressed);
+    if !clicked && !keys.any_just_pressed(bindings.next_layer.iter().copied()) {
+        return;
+    }
+
+    *active = ActiveBaseLayer::new(&config, (active.index + 1) % config.base_layers.len());
+    let layer = active.layer(&config);
+    info!("Switching to base layer {}", layer.name);
+
+    for entity in &tiles {
+        commands.entity(entity).despawn_recursive();
+    }
+    index.0.clear();
+    requested.0.clear();
+
+    for mut text in &mut labels {
+        text.sections[0].value = layer_label(layer);
+    }
+}
+
+// Credits the base layer and every visible overlay.
+pub fn update_attribution(
+    config: Res<MapConfig>,
+    active: Res<ActiveBaseLayer>,
+    overlays: Res<Overlays>,
+    offline: Res<OfflineSources>,
+    mut attributions: Query<&mut Text, With<AttributionText>>,
+) {
+    let attribution = attribution(&config, &active, &overlays, &offline);
+    for mut text in &mut attributions {
+        if text.sections[0].value != attribution {
+            text.sections[0].value = attribution.clone();
+        }
+    }
+}
+
+fn attribution(
+    config: &MapConfig,
+    active: &ActiveBaseLayer,
+    overlays: &Overlays,
+    offline: &OfflineSources,
+) -> String {
+    // Archive layers without a configured attribution use the one from the archive metadata.
+    let layer = active.layer(config);
+    let layer_attribution = match offline.0.get(&layer.name) {
+        Some(archive) if layer.attribution.is_empty() => archive
+            .metadata()
+            .attribution
+            .as_deref()
+            .unwrap_or_default(),
+        _ => layer.attribution.as_str(),
+    };
+    let mut attributions = vec![layer_attribution];
+    for (overlay, state) in config.overlays.iter().zip(&overlays.states) {
+        if state.visible && !overlay.attribution.is_empty() {
+            attributions.push(&overlay.attribution);
+        }
+    }
+    attributions.join(" | ")
+}
+
+fn layer_label(layer: &LayerConfig) -> String {
+    format!("Layer: {}", layer.name)
+}
@@ -1,28 +1,94 @@
-use bevy::color::palettes::basic::RED;
-use bevy::ecs::world;
 use bevy::input::common_conditions::*;
 use bevy::input::mouse::MouseMotion;
 use bevy::render::camera::ScalingMode;
-use bevy::sprite::Anchor;
 use bevy::window::CursorGrabMode;
-use bevy::{
-    prelude::*,
-    window::{WindowResized, WindowResolution},
-};
-use bevy_slippy_tiles::{
-    Coordinates, DownloadSlippyTilesEvent, Radius, SlippyTileCoordinates,
-    SlippyTileDownloadedEvent, SlippyTilesPlugin, SlippyTilesSettings, TileSize, ZoomLevel,
-};
+use bevy::{prelude::*, window::WindowResolution};
 
 use bevy::input::mouse::MouseWheel;
+use bevy::math::DVec2;
+use bevy::tasks::{block_on, futures_lite::future, Task, TaskPool, TaskPoolBuilder};
+use bevy::transform::TransformSystem;
+use bevy::utils::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+mod cache;
+mod config;
+mod download;
+mod fade;
+mod fallback;
+mod fly_to;
+mod gazetteer;
+mod geojson;
+mod go_to_box;
+mod keyboard;
+mod layers;
+mod mbtiles;
+mod nominatim;
+mod offline;
+mod overlays;
+mod place_popup;
+mod pmtiles;
+mod projection;
+mod readout;
+mod scale_bar;
+mod status;
+
+use cache::{fetch_tile, maintain_tile_cache, save_tile_cache_on_exit, TileCache};
+use config::{tile_file_name, tile_url, MapConfig, TileSize, ASSETS_DIRECTORY};
+use fade::{start_tile_fades, update_tile_fades};
+use fallback::{update_placeholders, Placeholders};
+use fly_to::{fly_to_configured_location, start_flights, update_flight, ActiveFlight, FlyTo};
+use gazetteer::{load_gazetteer, poll_gazetteer};
+use geojson::{setup_vector_layer, update_vector_layer};
+use go_to_box::{draw_place_markers, go_to_box_closed, setup_go_to_box, update_go_to_box};
+use keyboard::{keyboard_navigation, KeyBindings};
+use layers::{setup_layers, switch_base_layer, update_attribution, ActiveBaseLayer};
+use nominatim::setup_geocoder;
+use offline::{display_offline_tiles, load_tile, OfflineSources};
+use overlays::{
+    control_overlays, display_overlay_tiles, request_overlay_tiles, update_overlay_tiles, Overlays,
+};
+use place_popup::{open_place_popup, setup_place_popup, update_place_popup};
+use projection::{
+    lat_lon_to_meters, tile_world_size, tile_zoom_level, zoom_level_to_scale, LatLon, WorldOrigin,
+    MAX_ZOOM_LEVEL, MIN_ZOOM_LEVEL,
+};
+use readout::{setup_readout, update_readout, ReadoutClipboard};
+use scale_bar::{setup_scale_bar, update_scale_bar};
+use status::{detect_failed_tiles, setup_tile_status, update_tile_status, TileFailed};
 
 fn main() {
+    // `mapapp download ...` fills the tile cache of a region and `mapapp purge ...` empties it,
+    // `mapapp geocode ...` and `mapapp geocoder-mock ...` query and stand in for a geocoder,
+    // without opening the map.
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("download") => std::process::exit(download::run_command(args[1..].to_vec())),
+        Some("purge") => std::process::exit(cache::run_purge_command(args[1..].to_vec())),
+        Some("geocode") => std::process::exit(nominatim::run_geocode_command(args[1..].to_vec())),
+        Some("geocoder-mock") => {
+            std::process::exit(nominatim::run_mock_command(args[1..].to_vec()))
+        }
+        _ => {}
+    }
+
+    let config = MapConfig::from_args().unwrap_or_else(|err| {
+        eprintln!("Invalid configuration: {}", err);
+        std::process::exit(2);
+    });
+    let offline = OfflineSources::open(&config).unwrap_or_else(|err| {
+        eprintln!("Cannot open the tile archive {}", err);
+        std::process::exit(2);
+    });
+    let active_layer = ActiveBaseLayer::new(&config, 0);
+    let cache = TileCache::open(&config);
+
     App::new()
-        // Our slippy tiles settings and plugin
-        .insert_resource(SlippyTilesSettings::new(
-            "https://tile.openstreetmap.org", // Tile server endpoint.
-            "tiles/",                         // assets/ folder storing the slippy tile downloads.
-        ))
+        .insert_resource(active_layer)
+        .insert_resource(Overlays::new(&config))
+        .insert_resource(offline)
+        .insert_resource(cache)
+        .insert_resource(config)
         .add_plugins(DefaultPlugins.set(WindowPlugin {
             primary_window: Some(Window {
                 title: "Map Example".into(),
@@ -31,51 +97,235 @@ fn main() {
             }),
             ..default()
         }))
-        .add_plugins(SlippyTilesPlugin)
-        .add_systems(Startup, setup)
+        .init_resource::<KeyBindings>()
+        .init_resource::<DownloadPool>()
+        .init_resource::<WorldOrigin>()
+        .init_non_send_resource::<ReadoutClipboard>()
+        .add_event::<TileFailed>()
+        .add_event::<FlyTo>()
+        .add_systems(
+            Startup,
+            (
+                setup,
+                setup_layers,
+                setup_tile_status,
+                setup_scale_bar,
+                setup_readout,
+                setup_go_to_box,
+                load_gazetteer,
+                setup_geocoder,
+                setup_place_popup,
+                setup_vector_layer,
+                fly_to_configured_location,
+            ),
+        )
         .add_systems(
             Update,
             (
                 start_moving.run_if(input_just_pressed(MouseButton::Left)),
                 update_camera_move.run_if(input_pressed(MouseButton::Left)),
                 end_moving.run_if(input_just_released(MouseButton::Left)),
+                update_camera_inertia.run_if(not(input_pressed(MouseButton::Left))),
             )
                 .chain(),
         )
         .add_systems(Update, update_camera_zoom.run_if(run_if_scroll))
-        .add_systems(Update, display_tiles)
+        .add_systems(Update, keyboard_navigation.run_if(go_to_box_closed))
+        .add_systems(
+            Update,
+            (
+                poll_gazetteer,
+                update_go_to_box,
+                start_flights,
+                update_flight,
+            )
+                .chain(),
+        )
+        .add_systems(
+            Update,
+            (
+                update_scale_bar,
+                update_readout,
+                draw_place_markers,
+                update_vector_layer,
+            ),
+        )
+        .add_systems(
+            Update,
+            (
+                open_place_popup.run_if(input_just_pressed(MouseButton::Right)),
+                update_place_popup,
+            )
+                .chain(),
+        )
+        .add_systems(
+            Update,
+            (
+                switch_base_layer.run_if(go_to_box_closed),
+                request_visible_tiles,
+                display_tiles,
+                display_offline_tiles,
+                unload_tiles,
+                start_tile_fades,
+                update_tile_fades,
+                update_placeholders,
+                detect_failed_tiles,
+                update_tile_status,
+            )
+                .chain(),
+        )
+        .add_systems(
+            Update,
+            (
+                control_overlays.run_if(go_to_box_closed),
+                request_overlay_tiles,
+                display_overlay_tiles,
+                update_overlay_tiles,
+                update_attribution,
+            )
+                .chain(),
+        )
+        .add_systems(Update, maintain_tile_cache)
+        .add_systems(
+            PostUpdate,
+            recenter_world.before(TransformSystem::TransformPropagate),
+        )
+        .add_systems(Last, save_tile_cache_on_exit)
         .run();
 }
 
 #[derive(Component)]
 struct MainCamera;
 
-#[derive(Component)]
-struct TextBox;
-
 #[derive(Resource)]
 struct WorldState {
     position: Vec2,
     camera_position: Vec3,
-    world: Vec2,
+    // Whether the map is dragged, the drags starting over the interface do not move it.
+    dragging: bool,
+    // Zoom level of the most recent tile request, `None` until the first request is sent.
+    zoom_level: Option<u8>,
 }
 
-fn setup(
-    mut commands: Commands,
-    asset_server: Res<AssetServer>,
-    mut download_slippy_tile_events: EventWriter<DownloadSlippyTilesEvent>,
-) {
-    let zoom_level = ZoomLevel::L1;
-    let tile_size_x = 256;
-    let tile_size_y = 256;
-    let size_x = 2_i32.pow(zoom_level.to_u8() as u32);
-    let size_y = 2_i32.pow(zoom_level.to_u8() as u32);
-    let world_x = (tile_size_x * size_x) as f32;
-    let world_y = (tile_size_y * size_y) as f32;
-    print!(
-        "Setup. world_x={}, world_y={}, size_y={}, size_x={}",
-        world_x, world_y, size_x, size_y
-    );
+// Camera position and scale built in `setup`, restored when resetting the view.
+#[derive(Resource)]
+struct InitialView {
+    // Center of the view in projected meters.
+    center: DVec2,
+    scale: f32,
+}
+
+// Velocity of the camera while dragging, carried on as a decaying fling after release.
+#[derive(Resource)]
+struct PanInertia {
+    // Camera velocity in screen pixels per second.
+    velocity: Vec2,
+    // Exponential decay rate of the fling per second, higher values stop the map sooner.
+    friction: f32,
+    // Speed in screen pixels per second below which the fling stops.
+    min_speed: f32,
+}
+
+impl Default for PanInertia {
+    fn default() -> Self {
+        Self {
+            velocity: Vec2::ZERO,
+            friction: 4.0,
+            min_speed: 10.0,
+        }
+    }
+}
+
+// Identifies a slippy tile across all zoom levels.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct TileKey {
+    zoom_level: u8,
+    x: u32,
+    y: u32,
+}
+
+// Tiles that were already requested from the tile server.
+#[derive(Resource, Default)]
+struct RequestedTiles(HashSet<TileKey>);
+
+// Tile sprites currently spawned, at most one per tile.
+#[derive(Resource, Default)]
+struct TileIndex(HashMap<TileKey, Entity>);
+
+// A slippy tile sprite displayed on the map.
+#[derive(Component)]
+struct MapTile(TileKey);
+
+// Threads of the download pool.
+const DOWNLOAD_THREADS: usize = 4;
+
+// Tile downloads block their thread until the response arrives, they run on a pool of their own
+// so they do not hold up the asset loading on the IO task pool.
+#[derive(Resource, Deref)]
+struct DownloadPool(TaskPool);
+
+impl Default for DownloadPool {
+    fn default() -> Self {
+        Self(
+            TaskPoolBuilder::new()
+                .num_threads(DOWNLOAD_THREADS)
+                .thread_name("Tile download pool".into())
+                .build(),
+        )
+    }
+}
+
+// A base layer tile downloading on the download pool.
+#[derive(Component)]
+struct TileDownload {
+    // Base layer the tile was requested for.
+    layer: usize,
+    key: TileKey,
+    // Tile path relative to `assets/`.
+    path: PathBuf,
+    // Whether the tile is downloaded, or already cached and still fresh.
+    fetch: bool,
+    // Expiry the server asked for.
+    task: Task<Result<Option<u64>, String>>,
+}
+
+// Limits on the tile sprites, and their textures, kept alive around the viewport.
+#[derive(Resource)]
+struct TileBudget {
+    // Tiles beyond the viewport, at their own zoom level, kept before they are unloaded.
+    margin: u32,
+    // Tiles this many zoom levels away from the current one are unloaded.
+    max_zoom_distance: u8,
+    // GPU memory available to tile textures, the furthest tiles are unloaded once it is exceeded.
+    max_texture_bytes: usize,
+}
+
+impl Default for TileBudget {
+    fn default() -> Self {
+        Self {
+            margin: 1,
+            max_zoom_distance: 2,
+            max_texture_bytes: 256 * 1024 * 1024,
+        }
+    }
+}
+
+impl TileBudget {
+    fn max_tiles(&self, tile_size: TileSize) -> usize {
+        // Tiles are uploaded as RGBA8 textures.
+        let tile_pixels = tile_size.to_pixels() as usize;
+        let tile_bytes = tile_pixels * tile_pixels * 4;
+        self.max_texture_bytes / tile_bytes
+    }
+}
+
+const INITIAL_ZOOM_LEVEL: u8 = 1;
+
+fn setup(mut commands: Commands, origin: Res<WorldOrigin>) {
+    // Start over latitude/longitude (0, 0).
+    let center = lat_lon_to_meters(LatLon::new(0.0, 0.0));
+    let translation = origin.meters_to_world(center);
+    let scale = zoom_level_to_scale(INITIAL_ZOOM_LEVEL);
 
     // Components.
     commands.spawn((
@@ -84,48 +334,268 @@ fn setup(
             projection: OrthographicProjection {
                 far: 1000.,
                 near: -1000.,
-                scaling_mode: ScalingMode::Fixed {
-                    width: world_x,
-                    height: world_y,
-                },
+                // One screen pixel covers `scale` world units, so the scale maps directly to a zoom level.
+                scaling_mode: ScalingMode::WindowSize(1.0),
+                scale,
                 ..default()
             },
+            transform: Transform::from_xyz(translation.x, translation.y, 0.0),
             ..default()
         },
     ));
 
-    let slippy_tile_event = DownloadSlippyTilesEvent {
-        tile_size: TileSize::Normal, // Size of tiles - Normal = 256px, Large = 512px (not all tile servers).
-        zoom_level: zoom_level, // Map zoom level (L0 = entire world, L19 = closest zoom level).
-        coordinates: Coordinates::from_latitude_longitude(0.0, 0.0),
-        radius: Radius(1), // Request one layer of surrounding tiles (2 = two layers of surrounding tiles - 25 total, 3 = three layers of surrounding tiles - 49 total, etc).
-        use_cache: true, // Don't make request if already requested previously, or if file already exists in tiles directory.
-    };
-    download_slippy_tile_events.send(slippy_tile_event);
-
-    commands.spawn((TextBox, Text2dBundle::default()));
-
     // Resources.
+    commands.insert_resource(InitialView { center, scale });
+    commands.init_resource::<ActiveFlight>();
+    commands.init_resource::<PanInertia>();
+    commands.init_resource::<Placeholders>();
+    commands.init_resource::<RequestedTiles>();
+    commands.init_resource::<TileBudget>();
+    commands.init_resource::<TileIndex>();
     commands.insert_resource(WorldState {
         position: Vec2::default(),
         camera_position: Vec3::default(),
-        world: Vec2::new(world_x, world_y),
+        dragging: false,
+        zoom_level: None,
     });
 }
 
+// Derives the slippy zoom level from the projection scale and requests the missing tiles covering the window,
+// from the tile server or the archive of the active base layer.
+#[allow(clippy::too_many_arguments)]
+fn request_visible_tiles(
+    mut commands: Commands,
+    cameras: Query<(&Transform, &OrthographicProjection), With<MainCamera>>,
+    windows: Query<&Window>,
+    mut state: ResMut<WorldState>,
+    mut requested: ResMut<RequestedTiles>,
+    mut index: ResMut<TileIndex>,
+    mut cache: ResMut<TileCache>,
+    asset_server: Res<AssetServer>,
+    pool: Res<DownloadPool>,
+    origin: Res<WorldOrigin>,
+    config: Res<MapConfig>,
+    active_layer: Res<ActiveBaseLayer>,
+    offline: Res<OfflineSources>,
+) {
+    let (camera, projection) = cameras.single();
+    let layer = active_layer.layer(&config);
+    let archive = offline.0.get(&layer.name);
+    // Beyond the highest zoom level of the tile source, its tiles are magnified.
+    let max_zoom = archive.map_or(layer.max_zoom, |archive| {
+        archive.metadata().max_zoom.min(layer.max_zoom)
+    });
+    let tile_size = active_tile_size(&config, &active_layer, &offline);
+    let zoom_level = tile_zoom_level(projection.scale, tile_size).min(max_zoom);
+    if state.zoom_level != Some(zoom_level) {
+        info!("Zoom level changed to {}", zoom_level);
+        state.zoom_level = Some(zoom_level);
+    }
+
+    let mut visible = visible_tiles(&origin, camera, projection, windows.single(), zoom_level, 0);
+    if let Some(archive) = archive {
+        // Only the tiles inside the bounds and zoom range of the archive exist.
+        let Some(range) = archive.metadata().tile_range(zoom_level) else {
+            return;
+        };
+        let min = visible.min.max(range.min);
+        let max = visible.max.min(range.max);
+        if min.cmpgt(max).any() {
+            return;
+        }
+        visible = URect { min, max };
+    }
+    for x in visible.min.x..=visible.max.x {
+        for y in visible.min.y..=visible.max.y {
+            let key = TileKey { zoom_level, x, y };
+            if !requested.0.insert(key) {
+                continue;
+            }
+            if let Some(archive) = archive {
+                commands.spawn(load_tile(archive, active_layer.index, key));
+                continue;
+            }
+            // An expired tile is shown until its fresh copy is downloaded, which replaces it.
+            let download = download_tile(&pool, &config, &cache, &active_layer, key);
+            if cache.is_expired(&download.path) {
+                cache.touch(&download.path);
+                spawn_tile(
+                    &mut commands,
+                    &origin,
+                    &mut index,
+                    key,
+                    asset_server.load(download.path.clone()),
+                );
+            }
+            info!("Requesting slippy tile {}/{}/{}", zoom_level, x, y);
+            commands.spawn(download);
+        }
+    }
+}
+
+// Size of the tiles of the active base layer. Archive tiles are read at the size they were stored,
+// downloaded tiles at the configured size.
+fn active_tile_size(
+    config: &MapConfig,
+    active_layer: &ActiveBaseLayer,
+    offline: &OfflineSources,
+) -> TileSize {
+    if offline.0.contains_key(&active_layer.layer(config).name) {
+        TileSize::Normal
+    } else {
+        config.tile_size
+    }
+}
+
+// Starts downloading a tile of the active base layer. Tiles already in the cache are only
+// downloaded again once expired, otherwise the download finishes right away.
+fn download_tile(
+    pool: &DownloadPool,
+    config: &MapConfig,
+    cache: &TileCache,
+    active_layer: &ActiveBaseLayer,
+    key: TileKey,
+) -> TileDownload {
+    let TileKey { zoom_level, x, y } = key;
+    let tile_size = config.tile_size;
+    let path = active_layer
+        .directory
+        .join(tile_file_name(zoom_level, x, y, tile_size));
+    let file = Path::new(ASSETS_DIRECTORY).join(&path);
+    let url_template = active_layer.layer(config).sized_url_template(tile_size);
+    let url = tile_url(&url_template, zoom_level, x, y);
+    let fetch = !cache.is_cached(&path) || cache.is_expired(&path);
+    let task = pool.spawn(async move {
+        if !fetch {
+            return Ok(None);
+        }
+        fetch_tile(&url, &file)
+    });
+    TileDownload {
+        layer: active_layer.index,
+        key,
+        path,
+        fetch,
+        task,
+    }
+}
+
+// Range of tiles at the zoom level overlapping the window, grown by `margin` tiles on every side.
+fn visible_tiles(
+    origin: &WorldOrigin,
+    camera: &Transform,
+    projection: &OrthographicProjection,
+    window: &Window,
+    zoom_level: u8,
+    margin: u32,
+) -> URect {
+    // Visible rectangle of the world, then the range of tiles it overlaps.
+    let center = camera.translation.truncate();
+    let half_size = Vec2::new(window.width(), window.height()) * projection.scale / 2.0
+        + margin as f32 * tile_world_size(zoom_level);
+    let (min_x, min_y) =
+        origin.world_to_tile(center + Vec2::new(-half_size.x, half_size.y), zoom_level);
+    let (max_x, max_y) =
+        origin.world_to_tile(center + Vec2::new(half_size.x, -half_size.y), zoom_level);
+    URect::new(min_x, min_y, max_x, max_y)
+}
+
+// Despawns tiles that left the viewport or belong to stale zoom levels, dropping their texture handles
+// so the images are unloaded, then trims the remaining tiles down to the memory budget.
+#[allow(clippy::too_many_arguments)]
+fn unload_tiles(
+    mut commands: Commands,
+    cameras: Query<(&Transform, &OrthographicProjection), With<MainCamera>>,
+    windows: Query<&Window>,
+    tiles: Query<(Entity, &MapTile)>,
+    origin: Res<WorldOrigin>,
+    state: Res<WorldState>,
+    budget: Res<TileBudget>,
+    config: Res<MapConfig>,
+    active_layer: Res<ActiveBaseLayer>,
+    offline: Res<OfflineSources>,
+    mut requested: ResMut<RequestedTiles>,
+    mut index: ResMut<TileIndex>,
+) {
+    let Some(zoom_level) = state.zoom_level else {
+        return;
+    };
+    let (camera, projection) = cameras.single();
+    let window = windows.single();
+    let center = camera.translation.truncate();
+
+    let mut kept = Vec::new();
+    for (entity, MapTile(key)) in &tiles {
+        let visible = visible_tiles(
+            &origin,
+            camera,
+            projection,
+            window,
+            key.zoom_level,
+            budget.margin,
+        );
+        let stale = key.zoom_level.abs_diff(zoom_level) > budget.max_zoom_distance;
+        if stale || !visible.contains(UVec2::new(key.x, key.y)) {
+            commands.entity(entity).despawn_recursive();
+            requested.0.remove(key);
+            index.0.remove(key);
+        } else {
+            kept.push((entity, *key));
+        }
+    }
+
+    let max_tiles = budget.max_tiles(active_tile_size(&config, &active_layer, &offline));
+    if kept.len() <= max_tiles {
+        return;
+    }
+
+    // Unload tiles of other zoom levels first, then the ones furthest from the viewport center.
+    kept.sort_by(|(_, a), (_, b)| {
+        let a_distance = origin
+            .tile_to_world(a.x, a.y, a.zoom_level)
+            .distance(center);
+        let b_distance = origin
+            .tile_to_world(b.x, b.y, b.zoom_level)
+            .distance(center);
+        (a.zoom_level != zoom_level)
+            .cmp(&(b.zoom_level != zoom_level))
+            .then(a_distance.total_cmp(&b_distance))
+    });
+    info!(
+        "Tile budget exceeded, unloading {} tiles",
+        kept.len() - max_tiles
+    );
+    for (entity, key) in &kept[max_tiles..] {
+        commands.entity(*entity).despawn_recursive();
+        requested.0.remove(key);
+        index.0.remove(key);
+    }
+}
+
 fn start_moving(
     cameras: Query<&Transform, With<MainCamera>>,
     mut windows: Query<&mut Window>,
+    interactions: Query<&Interaction>,
     mut state: ResMut<WorldState>,
+    mut inertia: ResMut<PanInertia>,
 ) {
-    println!("Mouse pressed");
+    // Clicks on the interface are not for the map.
+    if interactions
+        .iter()
+        .any(|interaction| *interaction != Interaction::None)
+    {
+        return;
+    }
+    state.dragging = true;
+    // Grabbing the map stops any running fling.
+    inertia.velocity = Vec2::ZERO;
     let camera = cameras.single();
     let mut window = windows.single_mut();
     window.cursor.grab_mode = CursorGrabMode::Locked;
-    window.cursor_position().map(|pos| {
+    if let Some(pos) = window.cursor_position() {
         state.position = pos;
         state.camera_position = camera.translation;
-    });
+    }
 }
 
 fn end_moving(
@@ -133,151 +603,252 @@ fn end_moving(
     mut windows: Query<&mut Window>,
     mut state: ResMut<WorldState>,
 ) {
-    println!("Mouse released");
+    if !state.dragging {
+        return;
+    }
+    state.dragging = false;
     let camera = cameras.single();
     let mut window = windows.single_mut();
     window.cursor.grab_mode = CursorGrabMode::None;
-    window.cursor_position().map(|pos| {
+    if let Some(pos) = window.cursor_position() {
         state.position = pos;
         state.camera_position = camera.translation;
-    });
-
-    let lat = camera.translation.y / 10.;
-    let lon = camera.translation.x / 10.;
-    println!("Coordinates lat={}; lon={}", lat, lon);
-    info!(
-        "Requesting slippy tile for latitude/longitude: {:?}",
-        (lat, lon)
-    );
+    }
 }
 
 fn update_camera_move(
-    mut cameras: Query<(&mut Transform, &GlobalTransform, &mut Camera), With<MainCamera>>,
-    mut texts: Query<(&mut Transform, &mut Text), (With<TextBox>, Without<MainCamera>)>,
+    mut cameras: Query<(&mut Transform, &GlobalTransform, &Camera), With<MainCamera>>,
     state: Res<WorldState>,
     windows: Query<&Window>,
-    mut gizmos: Gizmos,
+    time: Res<Time>,
+    mut inertia: ResMut<PanInertia>,
     mut evr_motion: EventReader<MouseMotion>,
 ) {
+    if !state.dragging {
+        evr_motion.clear();
+        return;
+    }
+    // Track the drag velocity, smoothed over the last few frames so a single jittery event
+    // does not decide the fling. Screen y grows down while the camera y grows up.
+    let delta: Vec2 = evr_motion.read().map(|ev| ev.delta).sum();
+    if time.delta_seconds() > 0.0 {
+        let velocity = Vec2::new(-delta.x, delta.y) / time.delta_seconds();
+        inertia.velocity = inertia.velocity.lerp(velocity, 0.5);
+    }
+
     let window = windows.single();
     let Some(cursor_position) = window.cursor_position() else {
         return;
     };
 
-    println!("Begin update");
     let mut camera = cameras.single_mut();
-    let mut text = texts.single_mut();
 
     // Calculate a world position based on the cursor's position.
-    camera
-        .2
-        .viewport_to_world_2d(camera.1, cursor_position)
-        .map(|point| {
-            let radius = 25.0;
-            gizmos.circle_2d(point, radius, RED);
-            text.0.translation.x = point.x;
-            text.0.translation.y = point.y + radius;
-            text.1.sections = vec![TextSection {
-                value: point.to_string(),
-                style: TextStyle {
-                    font_size: 32.0,
-                    color: Color::Srgba(Srgba { ..RED }),
-                    ..default()
-                },
-            }];
-
-            camera
-                .2
-                .viewport_to_world_2d(camera.1, state.position)
-                .map(|start_point| {
-                    camera.0.translation.x = state.camera_position.x + start_point.x - point.x;
-                    camera.0.translation.y = state.camera_position.y + start_point.y - point.y;
-                });
-        });
-
-    //for ev in evr_motion.read() {
-    //    println!("Mouse moved: X: {} px, Y: {} px", ev.delta.x, ev.delta.y);
-    //    camera.0.translation.x -= ev.delta.x;
-    //    camera.0.translation.y += ev.delta.y;
-    //}
-
-    println!("Exit update");
+    if let (Some(point), Some(start_point)) = (
+        camera.2.viewport_to_world_2d(camera.1, cursor_position),
+        camera.2.viewport_to_world_2d(camera.1, state.position),
+    ) {
+        camera.0.translation.x = state.camera_position.x + start_point.x - point.x;
+        camera.0.translation.y = state.camera_position.y + start_point.y - point.y;
+    }
+}
+
+// Keeps the camera moving after the mouse is released, slowing down with the configured friction.
+fn update_camera_inertia(
+    mut cameras: Query<(&mut Transform, &OrthographicProjection), With<MainCamera>>,
+    time: Res<Time>,
+    mut inertia: ResMut<PanInertia>,
+) {
+    if inertia.velocity == Vec2::ZERO {
+        return;
+    }
+    if inertia.velocity.length() < inertia.min_speed {
+        inertia.velocity = Vec2::ZERO;
+        return;
+    }
+
+    let dt = time.delta_seconds();
+    let (mut transform, projection) = cameras.single_mut();
+    let offset = inertia.velocity * projection.scale * dt;
+    transform.translation.x += offset.x;
+    transform.translation.y += offset.y;
+    let friction = inertia.friction;
+    inertia.velocity *= (-friction * dt).exp();
+}
+
+// Distance of the camera from the world origin, in pixels, beyond which the origin moves under it.
+const RECENTER_DISTANCE: f32 = 10_000.0;
+
+// Moves the world origin under the camera once it strays far enough for the f32 world coordinates
+// around it to lose precision, shifting everything placed in the world by the same offset.
+fn recenter_world(
+    cameras: Query<(Entity, &OrthographicProjection), With<MainCamera>>,
+    mut transforms: Query<&mut Transform, (Without<Parent>, Without<Node>)>,
+    mut origin: ResMut<WorldOrigin>,
+    mut state: ResMut<WorldState>,
+) {
+    let (camera, projection) = cameras.single();
+    let Ok(camera_transform) = transforms.get(camera) else {
+        return;
+    };
+    let offset = camera_transform.translation.truncate();
+    if offset.length() < RECENTER_DISTANCE * projection.scale {
+        return;
+    }
+    origin.0 = origin.world_to_meters(offset);
+    for mut transform in &mut transforms {
+        transform.translation.x -= offset.x;
+        transform.translation.y -= offset.y;
+    }
+    state.camera_position -= offset.extend(0.0);
 }
 
 fn run_if_scroll(evr_scroll: EventReader<MouseWheel>) -> bool {
     !evr_scroll.is_empty()
 }
 
+// Zoom levels per scrolled line (mouse wheels) and per scrolled pixel (touchpads).
+const ZOOM_PER_LINE: f32 = 0.25;
+const ZOOM_PER_PIXEL: f32 = 0.005;
+
 fn update_camera_zoom(
-    mut cameras: Query<(&mut OrthographicProjection, &mut Camera), With<MainCamera>>,
+    mut cameras: Query<
+        (
+            &mut Transform,
+            &GlobalTransform,
+            &Camera,
+            &mut OrthographicProjection,
+        ),
+        With<MainCamera>,
+    >,
+    windows: Query<&Window>,
     mut evr_scroll: EventReader<MouseWheel>,
 ) {
     use bevy::input::mouse::MouseScrollUnit;
-    println!("Begin update");
-    for ev in evr_scroll.read() {
-        for mut camera in &mut cameras {
-            match ev.unit {
-                MouseScrollUnit::Line => {
-                    println!(
-                        "Scroll (line units): vertical: {}, horizontal: {}",
-                        ev.y, ev.x
-                    );
-                }
-                MouseScrollUnit::Pixel => {
-                    println!(
-                        "Scroll (pixel units): vertical: {}, horizontal: {}",
-                        ev.y, ev.x
-                    );
-                }
-            };
-            camera.0.scale += 0.1 * ev.y;
-        }
+    let zoom_delta: f32 = evr_scroll
+        .read()
+        .map(|ev| match ev.unit {
+            MouseScrollUnit::Line => ev.y * ZOOM_PER_LINE,
+            MouseScrollUnit::Pixel => ev.y * ZOOM_PER_PIXEL,
+        })
+        .sum();
+
+    let window = windows.single();
+    for (mut transform, global_transform, camera, mut projection) in &mut cameras {
+        // Scroll up zooms in, each step scales the view by the same factor at every zoom level.
+        let min_scale = zoom_level_to_scale(MAX_ZOOM_LEVEL);
+        let max_scale = zoom_level_to_scale(MIN_ZOOM_LEVEL);
+        let scale = (projection.scale * 2_f32.powf(-zoom_delta)).clamp(min_scale, max_scale);
+        let ratio = scale / projection.scale;
+        projection.scale = scale;
+
+        // Keep the world point under the cursor fixed, or the viewport center without a cursor.
+        let Some(anchor) = window
+            .cursor_position()
+            .and_then(|position| camera.viewport_to_world_2d(global_transform, position))
+        else {
+            continue;
+        };
+        let center = transform.translation.truncate();
+        let translation = anchor + (center - anchor) * ratio;
+        transform.translation.x = translation.x;
+        transform.translation.y = translation.y;
     }
-    println!("Exit update");
 }
 
+// Records the finished downloads in the tile cache and displays the requested ones, refreshed
+// tiles replace their expired copy on the map.
+#[allow(clippy::too_many_arguments)]
 fn display_tiles(
     mut commands: Commands,
     asset_server: Res<AssetServer>,
-    state: Res<WorldState>,
-    mut slippy_tile_downloaded_events: EventReader<SlippyTileDownloadedEvent>,
+    origin: Res<WorldOrigin>,
+    active_layer: Res<ActiveBaseLayer>,
+    requested: Res<RequestedTiles>,
+    mut index: ResMut<TileIndex>,
+    mut cache: ResMut<TileCache>,
+    mut downloads: Query<(Entity, &mut TileDownload)>,
+    mut failures: EventWriter<TileFailed>,
 ) {
-    for slippy_tile_downloaded_event in slippy_tile_downloaded_events.read() {
-        println!("Display tiles");
-        info!("Slippy tile fetched: {:?}", slippy_tile_downloaded_event);
-        let zoom_level = slippy_tile_downloaded_event.zoom_level;
-        // Convert our slippy tile position to pixels on the screen relative to the center tile.
-        let SlippyTileCoordinates {
-            x: center_x,
-            y: center_y,
-        } = Coordinates::from_latitude_longitude((0.0).into(), (0.0).into())
-            .get_slippy_tile_coordinates(zoom_level);
-        let SlippyTileCoordinates {
-            x: current_x,
-            y: current_y,
-        } = slippy_tile_downloaded_event
-            .coordinates
-            .get_slippy_tile_coordinates(zoom_level);
-
-        let tile_pixels = slippy_tile_downloaded_event.tile_size.to_pixels() as f32;
-        let transform_x = (center_x as f32 - current_x as f32) * tile_pixels - state.world.x / 2.0
-            + tile_pixels / 2.0;
-        let transform_y = (center_y as f32 - current_y as f32) * tile_pixels - state.world.y / 2.0
-            + tile_pixels / 2.0;
-        print!(
-            "pixels={}, current_x={}, current_y={}, center_x={}, center_y={}, x={}, y={}",
-            tile_pixels, current_x, current_y, center_x, center_y, transform_x, transform_y
+    for (entity, mut download) in &mut downloads {
+        let Some(result) = block_on(future::poll_once(&mut download.task)) else {
+            continue;
+        };
+        commands.entity(entity).despawn();
+        match result {
+            Ok(expires) if download.fetch => cache.record(&download.path, expires),
+            Ok(_) => cache.touch(&download.path),
+            Err(err) => {
+                warn!(
+                    "Tile {} failed to download: {}",
+                    download.path.display(),
+                    err
+                );
+                if download.layer == active_layer.index {
+                    failures.send(TileFailed(download.key));
+                }
+                continue;
+            }
+        }
+
+        // Downloads of a previous base layer, of unloaded tiles and ahead of a flight are only
+        // cached.
+        let key = download.key;
+        if download.layer != active_layer.index || !requested.0.contains(&key) {
+            continue;
+        }
+        info!(
+            "Slippy tile fetched: {}/{}/{}",
+            key.zoom_level, key.x, key.y
         );
 
-        // Add our slippy tile to the screen.
-        commands.spawn(SpriteBundle {
-            texture: asset_server.load(slippy_tile_downloaded_event.path.clone()),
-            transform: Transform::from_xyz(transform_x, transform_y, 0.0),
-            sprite: Sprite {
-                custom_size: Some(Vec2::new(tile_pixels, tile_pixels)),
-                ..default()
-            },
-            ..Default::default()
-        });
+        // The tile is already on the map, its sprite holds the handle for the same path,
+        // so reloading the asset refreshes the texture in place from the new download.
+        if index.0.contains_key(&key) {
+            if download.fetch {
+                asset_server.reload(download.path.clone());
+            }
+            continue;
+        }
+
+        let texture = asset_server.load(download.path.clone());
+        spawn_tile(&mut commands, &origin, &mut index, key, texture);
+    }
+}
+
+// Adds a tile sprite to the screen and to the tile index.
+fn spawn_tile(
+    commands: &mut Commands,
+    origin: &WorldOrigin,
+    index: &mut TileIndex,
+    key: TileKey,
+    texture: Handle<Image>,
+) {
+    // A fresher copy of a tile already on the map replaces its texture in place.
+    if let Some(entity) = index.0.get(&key) {
+        commands.entity(*entity).insert(texture);
+        return;
     }
+
+    // Tiles of every zoom level share the same world space, sharper tiles are drawn on top.
+    let TileKey { zoom_level, x, y } = key;
+    let position = origin.tile_to_world(x, y, zoom_level);
+    let size = tile_world_size(zoom_level);
+
+    // Add our slippy tile to the screen.
+    let entity = commands
+        .spawn((
+            MapTile(key),
+            SpriteBundle {
+                texture,
+                transform: Transform::from_xyz(position.x, position.y, zoom_level as f32),
+                sprite: Sprite {
+                    custom_size: Some(Vec2::new(size, size)),
+                    ..default()
+                },
+                ..Default::default()
+            },
+        ))
+        .id();
+    index.0.insert(key, entity);
 }
@@ -0,0 +1,185 @@
+// Offline raster tiles read from MBTiles files (SQLite databases with `tiles` and `metadata`
+// tables). MBTiles rows follow the TMS scheme, counting y from the south, so the slippy y is
+// flipped on every lookup.
+
+use std::fmt;
+use std::path::Path;
+use std::sync::Mutex;
+
+use bevy::prelude::*;
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+
+use crate::offline::{ArchiveMetadata, TileArchive};
+use crate::projection::MAX_ZOOM_LEVEL;
+use crate::TileKey;
+
+#[derive(Debug)]
+pub enum MbTilesError {
+    Sqlite(rusqlite::Error),
+    InvalidMetadata(String, String),
+}
+
+impl fmt::Display for MbTilesError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MbTilesError::Sqlite(err) => write!(f, "{}", err),
+            MbTilesError::InvalidMetadata(name, value) => {
+                write!(f, "invalid metadata {} = {:?}", name, value)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MbTilesError {}
+
+impl From<rusqlite::Error> for MbTilesError {
+    fn from(err: rusqlite::Error) -> Self {
+        MbTilesError::Sqlite(err)
+    }
+}
+
+pub struct MbTiles {
+    connection: Mutex<Connection>,
+    metadata: ArchiveMetadata,
+}
+
+impl MbTiles {
+    pub fn open(path: &Path) -> Result<Self, MbTilesError> {
+        let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        Self::from_connection(connection)
+    }
+
+    fn from_connection(connection: Connection) -> Result<Self, MbTilesError> {
+        let metadata = read_metadata(&connection)?;
+        Ok(Self {
+            connection: Mutex::new(connection),
+            metadata,
+        })
+    }
+}
+
+impl TileArchive for MbTiles {
+    fn metadata(&self) -> &ArchiveMetadata {
+        &self.metadata
+    }
+
+    fn read_tile(&self, key: TileKey) -> Result<Option<Vec<u8>>, String> {
+        let TileKey { zoom_level, x, y } = key;
+        let row = 2_u32.pow(zoom_level as u32) - 1 - y;
+        self.connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT tile_data FROM tiles \
+                 WHERE zoom_level = ?1 AND tile_column = ?2 AND tile_row = ?3",
+                params![zoom_level, x, row],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(|err| err.to_string())
+    }
+}
+
+fn read_metadata(connection: &Connection) -> Result<ArchiveMetadata, MbTilesError> {
+    let mut statement = connection.prepare("SELECT name, value FROM metadata")?;
+    let rows = statement.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    let mut metadata = ArchiveMetadata {
+        max_zoom: MAX_ZOOM_LEVEL,
+        ..default()
+    };
+    for row in rows {
+        let (name, value): (String, String) = row?;
+        let invalid = || MbTilesError::InvalidMetadata(name.clone(), value.clone());
+        match name.as_str() {
+            "bounds" => {
+                let bounds: Vec<f64> = value
+                    .split(',')
+                    .map(|part| part.trim().parse())
+                    .collect::<Result<_, _>>()
+                    .map_err(|_| invalid())?;
+                metadata.bounds = Some(bounds.try_into().map_err(|_| invalid())?);
+            }
+            "minzoom" => metadata.min_zoom = value.parse().map_err(|_| invalid())?,
+            "maxzoom" => metadata.max_zoom = value.parse().map_err(|_| invalid())?,
+            "attribution" => metadata.attribution = Some(value),
+            _ => {}
+        }
+    }
+    metadata.max_zoom = metadata.max_zoom.min(MAX_ZOOM_LEVEL);
+    Ok(metadata)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn database(metadata: &[(&str, &str)]) -> Connection {
+        let connection = Connection::open_in_memory().unwrap();
+        connection
+            .execute_batch(
+                "CREATE TABLE metadata (name TEXT, value TEXT);
+                 CREATE TABLE tiles (zoom_level INTEGER, tile_column INTEGER, tile_row INTEGER,
+                                     tile_data BLOB);
+                 INSERT INTO tiles VALUES (3, 2, 1, x'89504e47');",
+            )
+            .unwrap();
+        for (name, value) in metadata {
+            connection
+                .execute("INSERT INTO metadata VALUES (?1, ?2)", params![name, value])
+                .unwrap();
+        }
+        connection
+    }
+
+    #[test]
+    fn tiles_are_looked_up_in_the_tms_scheme() {
+        let mbtiles = MbTiles::from_connection(database(&[])).unwrap();
+        // Row 1 counted from the south is row 2^3 - 1 - 1 = 6 from the north.
+        let tile = mbtiles.read_tile(TileKey {
+            zoom_level: 3,
+            x: 2,
+            y: 6,
+        });
+        assert_eq!(
+            tile.unwrap().as_deref(),
+            Some(&[0x89, b'P', b'N', b'G'][..])
+        );
+        let missing = mbtiles.read_tile(TileKey {
+            zoom_level: 3,
+            x: 2,
+            y: 1,
+        });
+        assert_eq!(missing.unwrap(), None);
+    }
+
+    #[test]
+    fn metadata_is_parsed_and_zooms_are_clamped() {
+        let mbtiles = MbTiles::from_connection(database(&[
+            ("name", "Helsinki"),
+            ("bounds", "24.8, 60.1, 25.2, 60.3"),
+            ("minzoom", "3"),
+            ("maxzoom", "25"),
+            ("attribution", "© OpenStreetMap contributors"),
+        ]))
+        .unwrap();
+        let metadata = mbtiles.metadata();
+        assert_eq!(metadata.bounds, Some([24.8, 60.1, 25.2, 60.3]));
+        assert_eq!(metadata.min_zoom, 3);
+        assert_eq!(metadata.max_zoom, MAX_ZOOM_LEVEL);
+        assert_eq!(
+            metadata.attribution.as_deref(),
+            Some("© OpenStreetMap contributors")
+        );
+
+        let defaults = MbTiles::from_connection(database(&[])).unwrap();
+        assert_eq!(defaults.metadata().max_zoom, MAX_ZOOM_LEVEL);
+        assert_eq!(defaults.metadata().bounds, None);
+
+        for invalid in [("bounds", "24.8,60.1,25.2"), ("maxzoom", "high")] {
+            assert!(matches!(
+                MbTiles::from_connection(database(&[invalid])),
+                Err(MbTilesError::InvalidMetadata(..))
+            ));
+        }
+    }
+}
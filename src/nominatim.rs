@@ -0,0 +1,508 @@
+// Geocoding with a server speaking the Nominatim `/search` and `/reverse` JSON API, like
+// https://nominatim.openstreetmap.org, set with `geocoder_url`. The go-to box searches it for the
+// names the gazetteer does not know, see `go_to_box.rs`, and right-clicking the map asks it for the
+// address of a location, see `place_popup.rs`.
+//
+// mapapp geocode <query> | --reverse <latitude,longitude> [configuration flags]
+// mapapp geocoder-mock --gazetteer <places.tsv> [--port <port>] [configuration flags]
+//
+// `geocoder-mock` answers both endpoints from the gazetteer on localhost, to try the client and the
+// map without network access: `--geocoder-url http://127.0.0.1:8088`.
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+
+use bevy::prelude::*;
+use bevy::tasks::{IoTaskPool, Task};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::config::{ConfigError, MapConfig, USER_AGENT};
+use crate::fly_to::{parse_location, DEFAULT_ZOOM_LEVEL};
+use crate::gazetteer::{place_zoom_level, Place, PlaceIndex};
+use crate::projection::LatLon;
+
+// Places returned by a search.
+const SEARCH_LIMIT: usize = 8;
+// Tiles across the bounding box of a place the map flies to, and the closest zoom level to it.
+const PLACE_TILES: f64 = 4.0;
+const MAX_PLACE_ZOOM_LEVEL: u8 = 17;
+const DEFAULT_MOCK_PORT: u16 = 8088;
+
+#[derive(Clone, Debug)]
+pub struct GeocodedPlace {
+    // Name and address, from the place to the country.
+    pub name: String,
+    pub lat_lon: LatLon,
+    // Zoom level showing the whole place.
+    pub zoom_level: u8,
+}
+
+// A place of the JSON responses, the numbers are sent as strings.
+#[derive(Deserialize)]
+struct NominatimPlace {
+    lat: String,
+    lon: String,
+    display_name: String,
+    // South, north, west and east.
+    #[serde(default)]
+    boundingbox: Option<[String; 4]>,
+}
+
+impl NominatimPlace {
+    fn to_place(&self) -> Option<GeocodedPlace> {
+        let lat_lon = LatLon::new(self.lat.parse().ok()?, self.lon.parse().ok()?);
+        let zoom_level = self
+            .boundingbox
+            .as_ref()
+            .and_then(|bounds| {
+                let bounds: Vec<f64> = bounds
+                    .iter()
+                    .map(|value| value.parse().ok())
+                    .collect::<Option<_>>()?;
+                let span = (bounds[1] - bounds[0]).max(bounds[3] - bounds[2]);
+                (span > 0.0).then(|| (360.0 * PLACE_TILES / span).log2().floor())
+            })
+            .map_or(DEFAULT_ZOOM_LEVEL, |zoom_level| {
+                zoom_level.clamp(0.0, MAX_PLACE_ZOOM_LEVEL as f64) as u8
+            });
+        Some(GeocodedPlace {
+            name: self.display_name.clone(),
+            lat_lon,
+            zoom_level,
+        })
+    }
+}
+
+#[derive(Resource, Clone)]
+pub struct Geocoder {
+    base_url: String,
+}
+
+impl Geocoder {
+    pub fn new(config: &MapConfig) -> Option<Self> {
+        config.geocoder_url.as_ref().map(|url| Self {
+            base_url: url.trim_end_matches('/').to_string(),
+        })
+    }
+
+    pub fn search_blocking(&self, query: &str) -> Result<Vec<GeocodedPlace>, String> {
+        let url = format!(
+            "{}/search?format=json&limit={}&q={}",
+            self.base_url,
+            SEARCH_LIMIT,
+            encode_query_component(query)
+        );
+        let places: Vec<NominatimPlace> = self.fetch(&url)?;
+        Ok(places.iter().filter_map(NominatimPlace::to_place).collect())
+    }
+
+    // Address of the closest place at the detail of a zoom level, `None` where there is nothing
+    // to find, like at sea.
+    pub fn reverse_blocking(
+        &self,
+        lat_lon: LatLon,
+        zoom_level: u8,
+    ) -> Result<Option<GeocodedPlace>, String> {
+        let url = format!(
+            "{}/reverse?format=json&lat={:.6}&lon={:.6}&zoom={}",
+            self.base_url,
+            lat_lon.latitude,
+            lat_lon.longitude,
+            zoom_level.min(18)
+        );
+        let value: serde_json::Value = self.fetch(&url)?;
+        if value.get("error").is_some() {
+            return Ok(None);
+        }
+        let place: NominatimPlace =
+            serde_json::from_value(value).map_err(|err| format!("invalid response: {}", err))?;
+        Ok(place.to_place())
+    }
+
+    pub fn search(&self, query: &str) -> Task<Result<Vec<GeocodedPlace>, String>> {
+        let geocoder = self.clone();
+        let query = query.to_string();
+        IoTaskPool::get().spawn(async move { geocoder.search_blocking(&query) })
+    }
+
+    pub fn reverse(
+        &self,
+        lat_lon: LatLon,
+        zoom_level: u8,
+    ) -> Task<Result<Option<GeocodedPlace>, String>> {
+        let geocoder = self.clone();
+        IoTaskPool::get().spawn(async move { geocoder.reverse_blocking(lat_lon, zoom_level) })
+    }
+
+    fn fetch<T: DeserializeOwned>(&self, url: &str) -> Result<T, String> {
+        let mut request = ehttp::Request::get(url);
+        request.headers.insert("User-Agent", USER_AGENT);
+        let response = ehttp::fetch_blocking(&request)?;
+        if !response.ok {
+            return Err(format!(
+                "{} {} from {}",
+                response.status, response.status_text, url
+            ));
+        }
+        serde_json::from_slice(&response.bytes).map_err(|err| format!("invalid response: {}", err))
+    }
+}
+
+// Makes the configured geocoder available to the go-to box and the place popup, the map works
+// without one.
+pub fn setup_geocoder(mut commands: Commands, config: Res<MapConfig>) {
+    if let Some(geocoder) = Geocoder::new(&config) {
+        commands.insert_resource(geocoder);
+    }
+}
+
+enum GeocodeRequest {
+    Search(String),
+    Reverse(LatLon),
+}
+
+// Entry point of `mapapp geocode`, returns the process exit code.
+pub fn run_geocode_command(args: Vec<String>) -> i32 {
+    let mut args = args.into_iter();
+    let request = match args.next() {
+        Some(flag) if flag == "--reverse" => {
+            let value = args.next().unwrap_or_default();
+            parse_location(&value)
+                .map(|location| GeocodeRequest::Reverse(location.lat_lon))
+                .ok_or(ConfigError::InvalidValue(flag, value))
+        }
+        Some(query) if !query.starts_with("--") => Ok(GeocodeRequest::Search(query)),
+        _ => Err(ConfigError::MissingValue("<query>".into())),
+    };
+    let parsed = request.and_then(|request| {
+        MapConfig::parse_args(args.collect::<Vec<_>>()).map(|config| (request, config))
+    });
+    let (request, config) = match parsed {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            eprintln!("Invalid geocode request: {}", err);
+            return 2;
+        }
+    };
+    let Some(geocoder) = Geocoder::new(&config) else {
+        eprintln!("Invalid geocode request: no geocoder_url configured");
+        return 2;
+    };
+
+    let places = match request {
+        GeocodeRequest::Search(query) => geocoder.search_blocking(&query),
+        GeocodeRequest::Reverse(lat_lon) => geocoder
+            .reverse_blocking(lat_lon, DEFAULT_ZOOM_LEVEL)
+            .map(|place| place.into_iter().collect()),
+    };
+    match places {
+        Ok(places) if places.is_empty() => {
+            println!("No place found");
+            1
+        }
+        Ok(places) => {
+            for place in places {
+                println!(
+                    "{:.6}, {:.6}, {}  {}",
+                    place.lat_lon.latitude, place.lat_lon.longitude, place.zoom_level, place.name
+                );
+            }
+            0
+        }
+        Err(err) => {
+            eprintln!("Geocoding failed: {}", err);
+            1
+        }
+    }
+}
+
+// Entry point of `mapapp geocoder-mock`, serves requests until the process is stopped.
+pub fn run_mock_command(args: Vec<String>) -> i32 {
+    let mut port = DEFAULT_MOCK_PORT;
+    let mut remaining = Vec::new();
+    let mut args = args.into_iter();
+    let mut parsed = Ok(());
+    while let Some(flag) = args.next() {
+        let Some(value) = args.next() else {
+            parsed = Err(ConfigError::MissingValue(flag));
+            break;
+        };
+        match flag.as_str() {
+            "--port" => match value.parse() {
+                Ok(value) => port = value,
+                Err(_) => parsed = Err(ConfigError::InvalidValue(flag, value)),
+            },
+            _ => remaining.extend([flag, value]),
+        }
+    }
+    let config = match parsed.and_then(|()| MapConfig::parse_args(remaining)) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("Invalid geocoder mock request: {}", err);
+            return 2;
+        }
+    };
+    let Some(path) = &config.gazetteer else {
+        eprintln!("Invalid geocoder mock request: no gazetteer configured");
+        return 2;
+    };
+    let index = match PlaceIndex::load(Path::new(path)) {
+        Ok(index) => index,
+        Err(err) => {
+            eprintln!("Cannot load the gazetteer: {}", err);
+            return 1;
+        }
+    };
+    let listener = match TcpListener::bind(("127.0.0.1", port)) {
+        Ok(listener) => listener,
+        Err(err) => {
+            eprintln!("Cannot listen on port {}: {}", port, err);
+            return 1;
+        }
+    };
+
+    println!("Serving {} on http://127.0.0.1:{}", path, port);
+    serve_mock(&index, listener);
+    0
+}
+
+// Answers the requests of the listener one at a time, until the process is stopped.
+fn serve_mock(index: &PlaceIndex, listener: TcpListener) {
+    for stream in listener.incoming() {
+        if let Err(err) = stream.and_then(|stream| serve_mock_request(index, stream)) {
+            eprintln!("Request failed: {}", err);
+        }
+    }
+}
+
+fn serve_mock_request(index: &PlaceIndex, mut stream: TcpStream) -> std::io::Result<()> {
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // The headers are not needed, they are read up to the empty line ending them.
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 0 && !line.trim().is_empty() {
+        line.clear();
+    }
+
+    let target = request_line.split_whitespace().nth(1).unwrap_or("/");
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let params: HashMap<String, String> = query
+        .split('&')
+        .filter_map(|param| param.split_once('='))
+        .map(|(key, value)| (decode_query_component(key), decode_query_component(value)))
+        .collect();
+    let (status, body) = match path {
+        "/search" => {
+            let query = params.get("q").map_or("", String::as_str);
+            let limit = params
+                .get("limit")
+                .and_then(|limit| limit.parse().ok())
+                .unwrap_or(SEARCH_LIMIT);
+            let places: Vec<serde_json::Value> = index
+                .search(query, limit)
+                .into_iter()
+                .map(place_json)
+                .collect();
+            ("200 OK", serde_json::Value::from(places))
+        }
+        "/reverse" => {
+            let coordinate = |name: &str| params.get(name).and_then(|value| value.parse().ok());
+            let place = coordinate("lat")
+                .zip(coordinate("lon"))
+                .and_then(|(latitude, longitude)| index.nearest(LatLon::new(latitude, longitude)));
+            match place {
+                Some(place) => ("200 OK", place_json(place)),
+                None => ("200 OK", json!({ "error": "Unable to geocode" })),
+            }
+        }
+        _ => ("404 Not Found", json!({ "error": "Not found" })),
+    };
+    let body = body.to_string();
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+// A gazetteer place as Nominatim sends it, with a bounding box the client zooms to the zoom level
+// of the place for.
+fn place_json(place: &Place) -> serde_json::Value {
+    let span = 0.9 * 360.0 * PLACE_TILES / 2f64.powi(place_zoom_level(place) as i32);
+    let LatLon {
+        latitude,
+        longitude,
+    } = place.lat_lon;
+    let bounds = [
+        latitude - span / 2.0,
+        latitude + span / 2.0,
+        longitude - span / 2.0,
+        longitude + span / 2.0,
+    ];
+    json!({
+        "lat": latitude.to_string(),
+        "lon": longitude.to_string(),
+        "name": place.name,
+        "display_name": place.label(),
+        "boundingbox": bounds.map(|value| value.to_string()),
+    })
+}
+
+fn encode_query_component(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            b' ' => "+".into(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+fn decode_query_component(value: &str) -> String {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut rest = value.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        rest = tail;
+        match byte {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let hex = std::str::from_utf8(&rest[..rest.len().min(2)]).unwrap_or_default();
+                match u8::from_str_radix(hex, 16) {
+                    Ok(decoded) if hex.len() == 2 => {
+                        bytes.push(decoded);
+                        rest = &rest[2..];
+                    }
+                    _ => bytes.push(b'%'),
+                }
+            }
+            _ => bytes.push(byte),
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PLACES: &str = "name\tlatitude\tlongitude\tpopulation\tcountry
+Helsinki\t60.1699\t24.9384\t650000\tFI
+Espoo\t60.2055\t24.6559\t300000\tFI
+Paris\t48.8566\t2.3522\t2100000\tFR
+São Paulo\t-23.5505\t-46.6333\t12300000\tBR
+";
+
+    // Geocoder of a mock serving the places on an ephemeral port.
+    fn mock_geocoder(places: &str) -> Geocoder {
+        let index = PlaceIndex::parse(places).unwrap();
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let address = listener.local_addr().unwrap();
+        std::thread::spawn(move || serve_mock(&index, listener));
+        Geocoder {
+            base_url: format!("http://{}", address),
+        }
+    }
+
+    #[test]
+    fn search_through_the_mock() {
+        let geocoder = mock_geocoder(PLACES);
+        let places = geocoder.search_blocking("helsinki").unwrap();
+        assert_eq!(places.len(), 1);
+        assert_eq!(places[0].name, "Helsinki, FI");
+        assert!((places[0].lat_lon.latitude - 60.1699).abs() < 1e-9);
+        assert!((places[0].lat_lon.longitude - 24.9384).abs() < 1e-9);
+        // The bounding box of the mock zooms to the zoom level of the place.
+        assert_eq!(places[0].zoom_level, 11);
+
+        // Spaces and non-ASCII letters survive the query string.
+        let places = geocoder.search_blocking("São Paulo").unwrap();
+        assert_eq!(places[0].name, "São Paulo, BR");
+        assert_eq!(places[0].zoom_level, 10);
+
+        assert!(geocoder.search_blocking("nowhere").unwrap().is_empty());
+    }
+
+    #[test]
+    fn reverse_through_the_mock() {
+        let geocoder = mock_geocoder(PLACES);
+        let place = geocoder
+            .reverse_blocking(LatLon::new(60.2, 24.7), 12)
+            .unwrap()
+            .unwrap();
+        assert_eq!(place.name, "Espoo, FI");
+        assert_eq!(place.zoom_level, 11);
+    }
+
+    #[test]
+    fn reverse_error_response_is_no_place() {
+        let geocoder = mock_geocoder("name\tlatitude\tlongitude\n");
+        let place = geocoder.reverse_blocking(LatLon::new(0.0, 0.0), 12);
+        assert!(matches!(place, Ok(None)));
+    }
+
+    #[test]
+    fn bounding_box_gives_the_zoom_level() {
+        let zoom_level = |boundingbox: Option<[&str; 4]>| {
+            NominatimPlace {
+                lat: "60.0".into(),
+                lon: "25.0".into(),
+                display_name: "Place".into(),
+                boundingbox: boundingbox.map(|bounds| bounds.map(String::from)),
+            }
+            .to_place()
+            .unwrap()
+            .zoom_level
+        };
+        // Four tiles of zoom level 10 across the box.
+        let span = 360.0 * PLACE_TILES / 1024.0;
+        let bounds = ["60.0", "60.1", "25.0", &(25.0 + span * 0.9).to_string()];
+        assert_eq!(zoom_level(Some(bounds)), 10);
+        // The larger side decides.
+        let bounds = ["60.0", &(60.0 + span * 1.8).to_string(), "25.0", "25.1"];
+        assert_eq!(zoom_level(Some(bounds)), 9);
+        // Whole world, single points and missing or invalid boxes.
+        assert_eq!(zoom_level(Some(["-90", "90", "-180", "180"])), 2);
+        assert_eq!(zoom_level(Some(["-90", "90", "-180", "1800000"])), 0);
+        assert_eq!(
+            zoom_level(Some(["60.0", "60.0000001", "25.0", "25.0"])),
+            MAX_PLACE_ZOOM_LEVEL
+        );
+        assert_eq!(
+            zoom_level(Some(["60.0", "60.0", "25.0", "25.0"])),
+            DEFAULT_ZOOM_LEVEL
+        );
+        assert_eq!(zoom_level(None), DEFAULT_ZOOM_LEVEL);
+        assert_eq!(
+            zoom_level(Some(["north", "60.1", "25.0", "25.1"])),
+            DEFAULT_ZOOM_LEVEL
+        );
+    }
+
+    #[test]
+    fn query_components_round_trip() {
+        assert_eq!(encode_query_component("a b"), "a+b");
+        assert_eq!(encode_query_component("a&b=c"), "a%26b%3Dc");
+        assert_eq!(encode_query_component("São"), "S%C3%A3o");
+        assert_eq!(encode_query_component("A-z_0.9~"), "A-z_0.9~");
+        for value in ["São Paulo", "50% + 1", "a&b=c/d?e", ""] {
+            assert_eq!(
+                decode_query_component(&encode_query_component(value)),
+                value
+            );
+        }
+        // Broken escapes are kept as they are.
+        assert_eq!(decode_query_component("100%"), "100%");
+        assert_eq!(decode_query_component("%4"), "%4");
+        assert_eq!(decode_query_component("%zz"), "%zz");
+    }
+}
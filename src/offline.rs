@@ -0,0 +1,157 @@
+// Offline tile archives (MBTiles, PMTiles) backing base layers without a tile server. Tiles are
+// read and decoded on the async compute task pool, then displayed like downloaded tiles.
+
+use std::path::Path;
+use std::sync::Arc;
+
+use bevy::prelude::*;
+use bevy::render::render_asset::RenderAssetUsages;
+use bevy::render::texture::{CompressedImageFormats, ImageSampler, ImageType};
+use bevy::tasks::{block_on, futures_lite::future, AsyncComputeTaskPool, Task};
+use bevy::utils::HashMap;
+
+use crate::config::MapConfig;
+use crate::layers::ActiveBaseLayer;
+use crate::mbtiles::MbTiles;
+use crate::pmtiles::PmTiles;
+use crate::projection::{bounds_to_tiles, WorldOrigin};
+use crate::status::TileFailed;
+use crate::{spawn_tile, MapTile, TileIndex, TileKey};
+
+#[derive(Clone, Debug, Default)]
+pub struct ArchiveMetadata {
+    // Covered area as west, south, east, north in degrees.
+    pub bounds: Option<[f64; 4]>,
+    pub min_zoom: u8,
+    pub max_zoom: u8,
+    pub attribution: Option<String>,
+}
+
+impl ArchiveMetadata {
+    // Range of tiles at the zoom level inside the bounds, `None` outside the zoom range.
+    pub fn tile_range(&self, zoom_level: u8) -> Option<URect> {
+        if zoom_level < self.min_zoom || zoom_level > self.max_zoom {
+            return None;
+        }
+        let Some(bounds) = self.bounds else {
+            let last = 2_u32.pow(zoom_level as u32) - 1;
+            return Some(URect::new(0, 0, last, last));
+        };
+        Some(bounds_to_tiles(bounds, zoom_level))
+    }
+}
+
+pub trait TileArchive: Send + Sync {
+    fn metadata(&self) -> &ArchiveMetadata;
+
+    // Encoded image of the tile, `None` when the archive does not contain it.
+    fn read_tile(&self, key: TileKey) -> Result<Option<Vec<u8>>, String>;
+}
+
+// Decodes PNG and JPEG tiles, the formats of raster archives.
+pub fn decode_tile(data: &[u8]) -> Result<Image, String> {
+    let extension = if data.starts_with(b"\x89PNG") {
+        "png"
+    } else if data.starts_with(b"\xFF\xD8") {
+        "jpg"
+    } else {
+        return Err("unsupported tile image format".into());
+    };
+    Image::from_buffer(
+        data,
+        ImageType::Extension(extension),
+        CompressedImageFormats::NONE,
+        true,
+        ImageSampler::Default,
+        RenderAssetUsages::default(),
+    )
+    .map_err(|err| err.to_string())
+}
+
+// Open archives of the base layers, by layer name.
+#[derive(Resource, Default)]
+pub struct OfflineSources(pub HashMap<String, Arc<dyn TileArchive>>);
+
+impl OfflineSources {
+    pub fn open(config: &MapConfig) -> Result<Self, String> {
+        let mut sources = HashMap::new();
+        for layer in &config.base_layers {
+            let archive: Arc<dyn TileArchive> = match (&layer.mbtiles, &layer.pmtiles) {
+                (Some(path), _) => Arc::new(
+                    MbTiles::open(Path::new(path)).map_err(|err| format!("{}: {}", path, err))?,
+                ),
+                (_, Some(path)) => Arc::new(
+                    PmTiles::open(Path::new(path)).map_err(|err| format!("{}: {}", path, err))?,
+                ),
+                (None, None) => continue,
+            };
+            println!(
+                "Opened the archive of layer {}: {:?}",
+                layer.name,
+                archive.metadata()
+            );
+            sources.insert(layer.name.clone(), archive);
+        }
+        Ok(Self(sources))
+    }
+}
+
+#[derive(Component)]
+pub struct OfflineTileLoad {
+    layer: usize,
+    key: TileKey,
+    task: Task<Result<Option<Image>, String>>,
+}
+
+// Reads and decodes a tile on the async compute task pool.
+pub fn load_tile(archive: &Arc<dyn TileArchive>, layer: usize, key: TileKey) -> OfflineTileLoad {
+    let archive = archive.clone();
+    let task = AsyncComputeTaskPool::get().spawn(async move {
+        let data = archive.read_tile(key)?;
+        data.map(|data| decode_tile(&data)).transpose()
+    });
+    OfflineTileLoad { layer, key, task }
+}
+
+// Adds the tiles read from archives to the map, the same way downloaded tiles are displayed.
+pub fn display_offline_tiles(
+    mut commands: Commands,
+    mut images: ResMut<Assets<Image>>,
+    mut loads: Query<(Entity, &mut OfflineTileLoad)>,
+    active_layer: Res<ActiveBaseLayer>,
+    origin: Res<WorldOrigin>,
+    mut index: ResMut<TileIndex>,
+    mut failures: EventWriter<TileFailed>,
+) {
+    for (entity, mut load) in &mut loads {
+        let Some(result) = block_on(future::poll_once(&mut load.task)) else {
+            continue;
+        };
+        commands.entity(entity).despawn();
+
+        // Tiles read for a previous base layer are dropped.
+        if load.layer != active_layer.index {
+            continue;
+        }
+        match result {
+            Ok(Some(image)) => spawn_tile(
+                &mut commands,
+                &origin,
+                &mut index,
+                load.key,
+                images.add(image),
+            ),
+            Ok(None) => {
+                // Archives leave out the tiles without data, like the open sea. The tile is empty,
+                // it is indexed without a sprite so it is not waited for.
+                debug!("Tile {:?} is missing from the archive", load.key);
+                let entity = commands.spawn(MapTile(load.key)).id();
+                index.0.insert(load.key, entity);
+            }
+            Err(err) => {
+                warn!("Tile {:?} cannot be read: {}", load.key, err);
+                failures.send(TileFailed(load.key));
+            }
+        }
+    }
+}
@@ -0,0 +1,311 @@
+// Overlay layers: transparent tile sources (hillshade, transit lines, weather radar) stacked
+// over the base layer. Each overlay has its own z-order and opacity, can be toggled with the
+// number keys and faded with the bracket keys.
+//
+// Overlay tiles are downloaded on the download pool like the base layer tiles, into the same cache
+// layout.
+
+use std::path::{Path, PathBuf};
+
+use bevy::color::Alpha;
+use bevy::prelude::*;
+use bevy::tasks::{block_on, futures_lite::future, Task};
+use bevy::utils::{HashMap, HashSet};
+
+use crate::cache::{fetch_tile, TileCache};
+use crate::config::{tile_file_name, MapConfig, OverlayConfig, TileSize, ASSETS_DIRECTORY};
+use crate::keyboard::KeyBindings;
+use crate::projection::{scale_to_zoom_level, tile_world_size, WorldOrigin};
+use crate::{visible_tiles, DownloadPool, MainCamera, TileBudget, TileKey};
+
+// Overlays are drawn above every base layer zoom level, each one in its own band of z values.
+const OVERLAY_Z: f32 = 100.0;
+const OVERLAY_Z_BAND: f32 = 20.0;
+// Opacity change per second while an overlay fades in or out.
+const FADE_SPEED: f32 = 4.0;
+// Opacity change per key press.
+const OPACITY_STEP: f32 = 0.1;
+
+pub struct OverlayState {
+    pub visible: bool,
+    // Opacity of a fully faded in overlay.
+    pub opacity: f32,
+    // Opacity currently applied to the tiles, moves towards `opacity` or 0 when hidden.
+    pub current_opacity: f32,
+    // Base z value of the overlay tiles, from the rank of its z-order.
+    z: f32,
+    requested: HashSet<TileKey>,
+    tiles: HashMap<TileKey, Entity>,
+}
+
+#[derive(Resource)]
+pub struct Overlays {
+    pub states: Vec<OverlayState>,
+    // Overlay whose opacity is changed by the opacity keys, the last one toggled.
+    pub selected: usize,
+}
+
+impl Overlays {
+    pub fn new(config: &MapConfig) -> Self {
+        let mut ranks: Vec<usize> = (0..config.overlays.len()).collect();
+        ranks.sort_by_key(|&index| config.overlays[index].z_order);
+        let mut states: Vec<OverlayState> = config
+            .overlays
+            .iter()
+            .map(|overlay| OverlayState {
+                visible: overlay.visible,
+                opacity: overlay.opacity,
+                current_opacity: 0.0,
+                z: 0.0,
+                requested: HashSet::new(),
+                tiles: HashMap::new(),
+            })
+            .collect();
+        for (rank, index) in ranks.into_iter().enumerate() {
+            states[index].z = OVERLAY_Z + rank as f32 * OVERLAY_Z_BAND;
+        }
+        Self {
+            states,
+            selected: 0,
+        }
+    }
+}
+
+#[derive(Component)]
+pub struct OverlayTile {
+    overlay: usize,
+    key: TileKey,
+}
+
+#[derive(Component)]
+pub struct OverlayDownload {
+    overlay: usize,
+    key: TileKey,
+    path: String,
+    // Whether the tile is downloaded, or already cached and still fresh.
+    fetch: bool,
+    // Expiry the server asked for.
+    task: Task<Result<Option<u64>, String>>,
+}
+
+pub fn control_overlays(
+    keys: Res<ButtonInput<KeyCode>>,
+    bindings: Res<KeyBindings>,
+    config: Res<MapConfig>,
+    mut overlays: ResMut<Overlays>,
+) {
+    for (index, key) in bindings.toggle_overlays.iter().enumerate() {
+        if index < overlays.states.len() && keys.just_pressed(*key) {
+            let state = &mut overlays.states[index];
+            state.visible = !state.visible;
+            info!(
+                "Overlay {} {}",
+                config.overlays[index].name,
+                if state.visible { "shown" } else { "hidden" }
+            );
+            overlays.selected = index;
+        }
+    }
+
+    let selected = overlays.selected;
+    let Some(state) = overlays.states.get_mut(selected) else {
+        return;
+    };
+    if keys.any_just_pressed(bindings.overlay_opacity_down.iter().copied()) {
+        state.opacity = (state.opacity - OPACITY_STEP).max(0.0);
+    }
+    if keys.any_just_pressed(bindings.overlay_opacity_up.iter().copied()) {
+        state.opacity = (state.opacity + OPACITY_STEP).min(1.0);
+    }
+}
+
+// Starts downloading the missing overlay tiles covering the window.
+#[allow(clippy::too_many_arguments)]
+pub fn request_overlay_tiles(
+    mut commands: Commands,
+    cameras: Query<(&Transform, &OrthographicProjection), With<MainCamera>>,
+    windows: Query<&Window>,
+    origin: Res<WorldOrigin>,
+    config: Res<MapConfig>,
+    cache: Res<TileCache>,
+    pool: Res<DownloadPool>,
+    mut overlays: ResMut<Overlays>,
+) {
+    let (camera, projection) = cameras.single();
+    let window = windows.single();
+    for (index, (overlay, state)) in config
+        .overlays
+        .iter()
+        .zip(overlays.states.iter_mut())
+        .enumerate()
+    {
+        if !state.visible {
+            continue;
+        }
+        let zoom_level = scale_to_zoom_level(projection.scale).min(overlay.max_zoom);
+        let visible = visible_tiles(&origin, camera, projection, window, zoom_level, 0);
+        for x in visible.min.x..=visible.max.x {
+            for y in visible.min.y..=visible.max.y {
+                let key = TileKey { zoom_level, x, y };
+                if state.requested.insert(key) {
+                    let download =
+                        download_overlay_tile(&pool, &config, &cache, overlay, index, key);
+                    commands.spawn(download);
+                }
+            }
+        }
+    }
+}
+
+fn download_overlay_tile(
+    pool: &DownloadPool,
+    config: &MapConfig,
+    cache: &TileCache,
+    overlay: &OverlayConfig,
+    index: usize,
+    key: TileKey,
+) -> OverlayDownload {
+    let TileKey { zoom_level, x, y } = key;
+    let path = format!(
+        "{}{}",
+        config.layer_directory(&overlay.name),
+        tile_file_name(zoom_level, x, y, TileSize::Normal)
+    );
+    let file = PathBuf::from(ASSETS_DIRECTORY).join(&path);
+    let url = overlay.tile_url(zoom_level, x, y);
+    // Tiles already in the cache are only downloaded again once expired.
+    let fetch = !cache.is_cached(Path::new(&path)) || cache.is_expired(Path::new(&path));
+    let task = pool.spawn(async move {
+        if !fetch {
+            return Ok(None);
+        }
+        fetch_tile(&url, &file)
+    });
+    OverlayDownload {
+        overlay: index,
+        key,
+        path,
+        fetch,
+        task,
+    }
+}
+
+// Spawns the sprites of the overlay tiles whose download finished.
+pub fn display_overlay_tiles(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    origin: Res<WorldOrigin>,
+    mut downloads: Query<(Entity, &mut OverlayDownload)>,
+    mut cache: ResMut<TileCache>,
+    mut overlays: ResMut<Overlays>,
+) {
+    for (entity, mut download) in &mut downloads {
+        let Some(result) = block_on(future::poll_once(&mut download.task)) else {
+            continue;
+        };
+        commands.entity(entity).despawn();
+
+        // The overlay was hidden, or the tile unloaded, while downloading.
+        let state = &mut overlays.states[download.overlay];
+        if !state.requested.contains(&download.key) {
+            continue;
+        }
+        let path = Path::new(&download.path);
+        match result {
+            Ok(expires) if download.fetch => cache.record(path, expires),
+            Ok(_) => cache.touch(path),
+            Err(err) => {
+                warn!("Overlay tile {} failed to download: {}", download.path, err);
+                // An expired tile that could not be refreshed is still better than none.
+                if !cache.is_cached(path) {
+                    continue;
+                }
+            }
+        }
+
+        let TileKey { zoom_level, x, y } = download.key;
+        let position = origin.tile_to_world(x, y, zoom_level);
+        let size = tile_world_size(zoom_level);
+        let tile = commands
+            .spawn((
+                OverlayTile {
+                    overlay: download.overlay,
+                    key: download.key,
+                },
+                SpriteBundle {
+                    texture: asset_server.load(download.path.clone()),
+                    transform: Transform::from_xyz(
+                        position.x,
+                        position.y,
+                        state.z + zoom_level as f32,
+                    ),
+                    sprite: Sprite {
+                        color: Color::srgba(1.0, 1.0, 1.0, state.current_opacity),
+                        custom_size: Some(Vec2::new(size, size)),
+                        ..default()
+                    },
+                    ..default()
+                },
+            ))
+            .id();
+        state.tiles.insert(download.key, tile);
+    }
+}
+
+// Fades the overlays towards their opacity, or out when hidden, and unloads their tiles once
+// they are fully faded out, outside the viewport or from stale zoom levels.
+#[allow(clippy::too_many_arguments)]
+pub fn update_overlay_tiles(
+    mut commands: Commands,
+    cameras: Query<(&Transform, &OrthographicProjection), With<MainCamera>>,
+    windows: Query<&Window>,
+    mut tiles: Query<(Entity, &OverlayTile, &mut Sprite)>,
+    time: Res<Time>,
+    origin: Res<WorldOrigin>,
+    budget: Res<TileBudget>,
+    config: Res<MapConfig>,
+    mut overlays: ResMut<Overlays>,
+) {
+    let step = FADE_SPEED * time.delta_seconds();
+    for state in &mut overlays.states {
+        let target = if state.visible { state.opacity } else { 0.0 };
+        state.current_opacity = if state.current_opacity < target {
+            (state.current_opacity + step).min(target)
+        } else {
+            (state.current_opacity - step).max(target)
+        };
+    }
+
+    let (camera, projection) = cameras.single();
+    let window = windows.single();
+    for (entity, tile, mut sprite) in &mut tiles {
+        let state = &mut overlays.states[tile.overlay];
+        let zoom_level =
+            scale_to_zoom_level(projection.scale).min(config.overlays[tile.overlay].max_zoom);
+        let key = tile.key;
+        let visible = visible_tiles(
+            &origin,
+            camera,
+            projection,
+            window,
+            key.zoom_level,
+            budget.margin,
+        );
+        let faded_out = !state.visible && state.current_opacity == 0.0;
+        let stale = key.zoom_level.abs_diff(zoom_level) > budget.max_zoom_distance;
+        if faded_out || stale || !visible.contains(UVec2::new(key.x, key.y)) {
+            commands.entity(entity).despawn_recursive();
+            state.requested.remove(&key);
+            state.tiles.remove(&key);
+        } else {
+            sprite.color.set_alpha(state.current_opacity);
+        }
+    }
+
+    // Forget the pending downloads of hidden overlays, their results are dropped.
+    for state in &mut overlays.states {
+        if !state.visible {
+            state.requested.retain(|key| state.tiles.contains_key(key));
+        }
+    }
+}
@@ -0,0 +1,182 @@
+// Place popup: right-clicking the map shows the closest known place and its address next to the
+// cursor, with the coordinates of the clicked location. The address comes from the configured
+// geocoder, or without one the closest place of the gazetteer is shown with its distance.
+// The popup follows the location as the map moves, a left click or Escape closes it.
+
+use bevy::math::DVec2;
+use bevy::prelude::*;
+use bevy::tasks::{block_on, futures_lite::future, Task};
+
+use crate::config::MapConfig;
+use crate::fly_to::DEFAULT_ZOOM_LEVEL;
+use crate::gazetteer::Gazetteer;
+use crate::nominatim::{GeocodedPlace, Geocoder};
+use crate::projection::{great_circle_distance, meters_to_lat_lon, WorldOrigin, HALF_WORLD};
+use crate::scale_bar::format_distance;
+use crate::{MainCamera, WorldState};
+
+const LOOKING_UP: &str = "Looking up the address...";
+const NO_PLACE_FOUND: &str = "No place found";
+// Offset of the popup from the clicked location, in pixels.
+const POPUP_OFFSET: Vec2 = Vec2::new(12.0, 12.0);
+
+#[derive(Component)]
+pub struct PlacePopupBox;
+
+#[derive(Component)]
+pub struct PlacePopupText;
+
+#[derive(Resource, Default)]
+pub struct PlacePopup {
+    // Projected meters of the clicked location, `None` while the popup is closed.
+    point: Option<DVec2>,
+    // Closest place and address, or the state of the lookup.
+    place: String,
+    lookup: Option<Task<Result<Option<GeocodedPlace>, String>>>,
+}
+
+pub fn setup_place_popup(mut commands: Commands) {
+    commands.init_resource::<PlacePopup>();
+    commands
+        .spawn((
+            PlacePopupBox,
+            NodeBundle {
+                style: Style {
+                    position_type: PositionType::Absolute,
+                    max_width: Val::Px(320.0),
+                    padding: UiRect::all(Val::Px(6.0)),
+                    ..default()
+                },
+                background_color: Color::srgba(1.0, 1.0, 1.0, 0.9).into(),
+                visibility: Visibility::Hidden,
+                ..default()
+            },
+        ))
+        .with_children(|parent| {
+            parent.spawn((
+                PlacePopupText,
+                TextBundle::from_section(
+                    "",
+                    TextStyle {
+                        font_size: 14.0,
+                        color: Color::BLACK,
+                        ..default()
+                    },
+                ),
+            ));
+        });
+}
+
+// Opens the popup at the location under the cursor, on right click.
+#[allow(clippy::too_many_arguments)]
+pub fn open_place_popup(
+    cameras: Query<(&Camera, &GlobalTransform), With<MainCamera>>,
+    windows: Query<&Window>,
+    interactions: Query<&Interaction>,
+    origin: Res<WorldOrigin>,
+    state: Res<WorldState>,
+    config: Res<MapConfig>,
+    gazetteer: Res<Gazetteer>,
+    geocoder: Option<Res<Geocoder>>,
+    mut popup: ResMut<PlacePopup>,
+) {
+    // Clicks on the interface are not for the map.
+    if interactions
+        .iter()
+        .any(|interaction| *interaction != Interaction::None)
+    {
+        return;
+    }
+    let (camera, camera_transform) = cameras.single();
+    let Some(point) = windows
+        .single()
+        .cursor_position()
+        .and_then(|cursor| camera.viewport_to_world_2d(camera_transform, cursor))
+        .map(|point| origin.world_to_meters(point))
+        .filter(|point| point.abs().max_element() <= HALF_WORLD)
+    else {
+        return;
+    };
+    let lat_lon = meters_to_lat_lon(point);
+
+    popup.point = Some(point);
+    popup.lookup = None;
+    popup.place = match (&geocoder, &gazetteer.index) {
+        (Some(geocoder), _) => {
+            let zoom_level = state.zoom_level.unwrap_or(DEFAULT_ZOOM_LEVEL);
+            popup.lookup = Some(geocoder.reverse(lat_lon, zoom_level));
+            LOOKING_UP.into()
+        }
+        (None, Some(index)) => match index.nearest(lat_lon) {
+            Some(place) => format!(
+                "{} from {}",
+                format_distance(great_circle_distance(lat_lon, place.lat_lon), config.units),
+                place.label()
+            ),
+            None => NO_PLACE_FOUND.into(),
+        },
+        (None, None) => "No geocoder or gazetteer configured".into(),
+    };
+}
+
+pub fn update_place_popup(
+    cameras: Query<(&Camera, &GlobalTransform), With<MainCamera>>,
+    mouse_buttons: Res<ButtonInput<MouseButton>>,
+    keys: Res<ButtonInput<KeyCode>>,
+    origin: Res<WorldOrigin>,
+    mut popup: ResMut<PlacePopup>,
+    mut boxes: Query<(&mut Style, &mut Visibility), With<PlacePopupBox>>,
+    mut texts: Query<&mut Text, With<PlacePopupText>>,
+) {
+    if popup.point.is_some()
+        && (mouse_buttons.just_pressed(MouseButton::Left) || keys.just_pressed(KeyCode::Escape))
+    {
+        popup.point = None;
+        popup.lookup = None;
+    }
+    let (mut style, mut visibility) = boxes.single_mut();
+    let Some(point) = popup.point else {
+        *visibility = Visibility::Hidden;
+        return;
+    };
+
+    let looked_up = popup
+        .lookup
+        .as_mut()
+        .and_then(|task| block_on(future::poll_once(task)));
+    if let Some(result) = looked_up {
+        popup.lookup = None;
+        popup.place = match result {
+            Ok(Some(place)) => place.name,
+            Ok(None) => NO_PLACE_FOUND.into(),
+            Err(err) => {
+                warn!("Reverse geocoding failed: {}", err);
+                format!("Lookup failed: {}", err)
+            }
+        };
+    }
+
+    // The popup is hidden while its location is out of the window.
+    let (camera, camera_transform) = cameras.single();
+    let viewport = camera.logical_viewport_size().unwrap_or_default();
+    let Some(position) = camera
+        .world_to_viewport(camera_transform, origin.meters_to_world(point).extend(0.0))
+        .filter(|position| position.cmpge(Vec2::ZERO).all() && position.cmple(viewport).all())
+    else {
+        *visibility = Visibility::Hidden;
+        return;
+    };
+    *visibility = Visibility::Inherited;
+    style.left = Val::Px(position.x + POPUP_OFFSET.x);
+    style.top = Val::Px(position.y + POPUP_OFFSET.y);
+
+    let lat_lon = meters_to_lat_lon(point);
+    let value = format!(
+        "{}\n{:.6}°, {:.6}°",
+        popup.place, lat_lon.latitude, lat_lon.longitude
+    );
+    let mut text = texts.single_mut();
+    if text.sections[0].value != value {
+        text.sections[0].value = value;
+    }
+}
@@ -0,0 +1,597 @@
+// Offline raster tiles read from PMTiles v3 archives: a fixed size header, a compressed root
+// directory, optional compressed leaf directories and the tile data, addressed by tile ids along
+// a Hilbert curve. See https://github.com/protomaps/PMTiles/blob/main/spec/v3/spec.md
+
+use std::fmt;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use bevy::utils::HashMap;
+use flate2::read::GzDecoder;
+
+use crate::offline::{ArchiveMetadata, TileArchive};
+use crate::projection::MAX_ZOOM_LEVEL;
+use crate::TileKey;
+
+const HEADER_LENGTH: usize = 127;
+const MAGIC: &[u8] = b"PMTiles";
+const VERSION: u8 = 3;
+// Root, leaf and leaf of leaf directories.
+const MAX_DIRECTORY_DEPTH: usize = 3;
+// Longest directory, metadata or tile read, the lengths come from the archive and a corrupt one
+// would make the reader allocate them.
+const MAX_READ_LENGTH: u64 = 64 * 1024 * 1024;
+
+#[derive(Debug)]
+pub enum PmTilesError {
+    Io(std::io::Error),
+    InvalidHeader(String),
+    UnsupportedCompression(u8),
+    UnsupportedTileType(u8),
+    InvalidDirectory,
+    InvalidMetadata(serde_json::Error),
+    // Offset and length of a read beyond the end of the archive or `MAX_READ_LENGTH`.
+    InvalidRange(u64, u64),
+}
+
+impl fmt::Display for PmTilesError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PmTilesError::Io(err) => write!(f, "{}", err),
+            PmTilesError::InvalidHeader(reason) => write!(f, "invalid header: {}", reason),
+            PmTilesError::UnsupportedCompression(compression) => {
+                write!(f, "unsupported compression {}", compression)
+            }
+            PmTilesError::UnsupportedTileType(tile_type) => {
+                write!(
+                    f,
+                    "unsupported tile type {}, only png and jpeg are displayed",
+                    tile_type
+                )
+            }
+            PmTilesError::InvalidDirectory => write!(f, "invalid directory"),
+            PmTilesError::InvalidMetadata(err) => write!(f, "invalid metadata: {}", err),
+            PmTilesError::InvalidRange(offset, length) => {
+                write!(f, "invalid range of {} bytes at {}", length, offset)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PmTilesError {}
+
+impl From<std::io::Error> for PmTilesError {
+    fn from(err: std::io::Error) -> Self {
+        PmTilesError::Io(err)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Compression {
+    None,
+    Gzip,
+}
+
+impl Compression {
+    fn from_u8(value: u8) -> Result<Self, PmTilesError> {
+        match value {
+            // 0 is "unknown", treated as uncompressed like the reference readers do.
+            0 | 1 => Ok(Compression::None),
+            2 => Ok(Compression::Gzip),
+            _ => Err(PmTilesError::UnsupportedCompression(value)),
+        }
+    }
+
+    fn decompress(self, data: Vec<u8>) -> Result<Vec<u8>, PmTilesError> {
+        match self {
+            Compression::None => Ok(data),
+            Compression::Gzip => {
+                let mut decompressed = Vec::new();
+                GzDecoder::new(data.as_slice()).read_to_end(&mut decompressed)?;
+                Ok(decompressed)
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Header {
+    root_directory_offset: u64,
+    root_directory_length: u64,
+    metadata_offset: u64,
+    metadata_length: u64,
+    leaf_directories_offset: u64,
+    tile_data_offset: u64,
+    internal_compression: Compression,
+    tile_compression: Compression,
+    min_zoom: u8,
+    max_zoom: u8,
+    // West, south, east, north in degrees.
+    bounds: [f64; 4],
+}
+
+impl Header {
+    fn parse(bytes: &[u8]) -> Result<Self, PmTilesError> {
+        if bytes.len() < HEADER_LENGTH || &bytes[..MAGIC.len()] != MAGIC {
+            return Err(PmTilesError::InvalidHeader("not a PMTiles archive".into()));
+        }
+        if bytes[7] != VERSION {
+            return Err(PmTilesError::InvalidHeader(format!(
+                "version {} is not supported",
+                bytes[7]
+            )));
+        }
+        let u64_at =
+            |offset: usize| u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap());
+        let degrees_at = |offset: usize| {
+            i32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap()) as f64 / 1e7
+        };
+
+        // Raster tiles only: png (2) and jpeg (3).
+        let tile_type = bytes[99];
+        if tile_type != 2 && tile_type != 3 {
+            return Err(PmTilesError::UnsupportedTileType(tile_type));
+        }
+        Ok(Self {
+            root_directory_offset: u64_at(8),
+            root_directory_length: u64_at(16),
+            metadata_offset: u64_at(24),
+            metadata_length: u64_at(32),
+            leaf_directories_offset: u64_at(40),
+            tile_data_offset: u64_at(56),
+            internal_compression: Compression::from_u8(bytes[97])?,
+            tile_compression: Compression::from_u8(bytes[98])?,
+            min_zoom: bytes[100],
+            max_zoom: bytes[101],
+            bounds: [
+                degrees_at(102),
+                degrees_at(106),
+                degrees_at(110),
+                degrees_at(114),
+            ],
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Entry {
+    tile_id: u64,
+    offset: u64,
+    length: u64,
+    // Number of consecutive tile ids sharing the data, 0 for a leaf directory.
+    run_length: u64,
+}
+
+fn read_varint(bytes: &mut &[u8]) -> Result<u64, PmTilesError> {
+    let mut value = 0_u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = bytes.split_first().ok_or(PmTilesError::InvalidDirectory)?;
+        *bytes = rest;
+        // The tenth byte holds the last bit of a u64.
+        if shift == 63 && byte > 1 {
+            return Err(PmTilesError::InvalidDirectory);
+        }
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(PmTilesError::InvalidDirectory)
+}
+
+// Directories store the tile ids as deltas, then the run lengths, the lengths and the offsets,
+// where an offset of 0 means the data directly follows the previous entry.
+fn parse_directory(mut bytes: &[u8]) -> Result<Vec<Entry>, PmTilesError> {
+    let count = read_varint(&mut bytes)? as usize;
+    let mut entries = Vec::with_capacity(count.min(bytes.len()));
+    let mut tile_id = 0;
+    for _ in 0..count {
+        tile_id += read_varint(&mut bytes)?;
+        entries.push(Entry {
+            tile_id,
+            offset: 0,
+            length: 0,
+            run_length: 0,
+        });
+    }
+    for entry in &mut entries {
+        entry.run_length = read_varint(&mut bytes)?;
+    }
+    for entry in &mut entries {
+        entry.length = read_varint(&mut bytes)?;
+    }
+    for index in 0..entries.len() {
+        let value = read_varint(&mut bytes)?;
+        entries[index].offset = if value == 0 && index > 0 {
+            entries[index - 1].offset + entries[index - 1].length
+        } else {
+            value.checked_sub(1).ok_or(PmTilesError::InvalidDirectory)?
+        };
+    }
+    Ok(entries)
+}
+
+// Entry covering the tile id: the last entry starting at or before it, when it is a leaf
+// directory or its run reaches the tile id.
+fn find_entry(entries: &[Entry], tile_id: u64) -> Option<Entry> {
+    let index = entries.partition_point(|entry| entry.tile_id <= tile_id);
+    let entry = *entries.get(index.checked_sub(1)?)?;
+    if entry.run_length == 0 || tile_id - entry.tile_id < entry.run_length {
+        Some(entry)
+    } else {
+        None
+    }
+}
+
+// Position of the tile along the Hilbert curves of all zoom levels up to its own.
+fn tile_id(key: TileKey) -> u64 {
+    let TileKey {
+        zoom_level,
+        mut x,
+        mut y,
+    } = key;
+    let mut id = ((1_u64 << (2 * zoom_level as u32)) - 1) / 3;
+    if zoom_level == 0 {
+        return id;
+    }
+    let mut s = 1_u32 << (zoom_level - 1);
+    while s > 0 {
+        let rx = x & s;
+        let ry = y & s;
+        id += ((3 * rx) ^ ry) as u64 * s as u64;
+        // Rotate the quadrant so the curve continues from the previous one.
+        if ry == 0 {
+            if rx != 0 {
+                // Only the bits below `s` are used from now on, so the subtraction may wrap.
+                x = (s - 1).wrapping_sub(x);
+                y = (s - 1).wrapping_sub(y);
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        s >>= 1;
+    }
+    id
+}
+
+pub struct PmTiles {
+    file: Mutex<File>,
+    // Size of the archive in bytes.
+    size: u64,
+    header: Header,
+    root_directory: Vec<Entry>,
+    // Leaf directories already read, by offset.
+    leaf_directories: Mutex<HashMap<u64, Arc<Vec<Entry>>>>,
+    metadata: ArchiveMetadata,
+}
+
+impl PmTiles {
+    pub fn open(path: &Path) -> Result<Self, PmTilesError> {
+        let mut file = File::open(path)?;
+        let size = file.metadata()?.len();
+        let mut bytes = [0; HEADER_LENGTH];
+        file.read_exact(&mut bytes)?;
+        let header = Header::parse(&bytes)?;
+
+        let root_directory = read_at(
+            &mut file,
+            size,
+            header.root_directory_offset,
+            header.root_directory_length,
+        )?;
+        let root_directory =
+            parse_directory(&header.internal_compression.decompress(root_directory)?)?;
+
+        // The JSON metadata is free form, only the attribution is used.
+        let attribution = if header.metadata_length > 0 {
+            let metadata = read_at(
+                &mut file,
+                size,
+                header.metadata_offset,
+                header.metadata_length,
+            )?;
+            let metadata = header.internal_compression.decompress(metadata)?;
+            let metadata: serde_json::Value =
+                serde_json::from_slice(&metadata).map_err(PmTilesError::InvalidMetadata)?;
+            metadata["attribution"].as_str().map(str::to_string)
+        } else {
+            None
+        };
+        let metadata = ArchiveMetadata {
+            bounds: Some(header.bounds),
+            min_zoom: header.min_zoom,
+            max_zoom: header.max_zoom.min(MAX_ZOOM_LEVEL),
+            attribution,
+        };
+
+        Ok(Self {
+            file: Mutex::new(file),
+            size,
+            header,
+            root_directory,
+            leaf_directories: Mutex::new(HashMap::new()),
+            metadata,
+        })
+    }
+
+    fn read(&self, offset: u64, length: u64) -> Result<Vec<u8>, PmTilesError> {
+        read_at(&mut *self.file.lock().unwrap(), self.size, offset, length)
+    }
+
+    fn leaf_directory(&self, offset: u64, length: u64) -> Result<Arc<Vec<Entry>>, PmTilesError> {
+        if let Some(entries) = self.leaf_directories.lock().unwrap().get(&offset) {
+            return Ok(entries.clone());
+        }
+        let bytes = self.read(self.header.leaf_directories_offset + offset, length)?;
+        let entries = Arc::new(parse_directory(
+            &self.header.internal_compression.decompress(bytes)?,
+        )?);
+        self.leaf_directories
+            .lock()
+            .unwrap()
+            .insert(offset, entries.clone());
+        Ok(entries)
+    }
+
+    fn find_tile(&self, key: TileKey) -> Result<Option<Vec<u8>>, PmTilesError> {
+        let tile_id = tile_id(key);
+        let mut leaf_directory;
+        let mut entries = self.root_directory.as_slice();
+        for _ in 0..MAX_DIRECTORY_DEPTH {
+            let Some(entry) = find_entry(entries, tile_id) else {
+                return Ok(None);
+            };
+            if entry.run_length > 0 {
+                let data = self.read(self.header.tile_data_offset + entry.offset, entry.length)?;
+                return self.header.tile_compression.decompress(data).map(Some);
+            }
+            leaf_directory = self.leaf_directory(entry.offset, entry.length)?;
+            entries = leaf_directory.as_slice();
+        }
+        Err(PmTilesError::InvalidDirectory)
+    }
+}
+
+impl TileArchive for PmTiles {
+    fn metadata(&self) -> &ArchiveMetadata {
+        &self.metadata
+    }
+
+    fn read_tile(&self, key: TileKey) -> Result<Option<Vec<u8>>, String> {
+        self.find_tile(key).map_err(|err| err.to_string())
+    }
+}
+
+// Reads `length` bytes at `offset` of an archive of `size` bytes, after checking the range.
+fn read_at(
+    file: &mut (impl Read + Seek),
+    size: u64,
+    offset: u64,
+    length: u64,
+) -> Result<Vec<u8>, PmTilesError> {
+    let end = offset.checked_add(length);
+    if length > MAX_READ_LENGTH || end.is_none_or(|end| end > size) {
+        return Err(PmTilesError::InvalidRange(offset, length));
+    }
+    let mut bytes = vec![0; length as usize];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+
+    use flate2::write::GzEncoder;
+
+    use super::*;
+
+    fn write_varint(mut value: u64, bytes: &mut Vec<u8>) {
+        while value >= 0x80 {
+            bytes.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        bytes.push(value as u8);
+    }
+
+    // Directory of (tile id, run length, length, offset) entries, with delta coded tile ids and
+    // the offsets of contiguous entries left out.
+    fn encode_directory(entries: &[(u64, u64, u64, u64)]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_varint(entries.len() as u64, &mut bytes);
+        let mut last_id = 0;
+        for &(tile_id, ..) in entries {
+            write_varint(tile_id - last_id, &mut bytes);
+            last_id = tile_id;
+        }
+        for &(_, run_length, ..) in entries {
+            write_varint(run_length, &mut bytes);
+        }
+        for &(_, _, length, _) in entries {
+            write_varint(length, &mut bytes);
+        }
+        let mut next_offset = None;
+        for &(_, _, length, offset) in entries {
+            let contiguous = next_offset == Some(offset);
+            write_varint(if contiguous { 0 } else { offset + 1 }, &mut bytes);
+            next_offset = Some(offset + length);
+        }
+        bytes
+    }
+
+    fn gzip(bytes: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(bytes).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn header_with(version: u8, tile_type: u8) -> Vec<u8> {
+        let mut bytes = vec![0; HEADER_LENGTH];
+        bytes[..7].copy_from_slice(MAGIC);
+        bytes[7] = version;
+        bytes[97] = 2;
+        bytes[98] = 1;
+        bytes[99] = tile_type;
+        bytes
+    }
+
+    fn header_bytes() -> Vec<u8> {
+        header_with(VERSION, 2)
+    }
+
+    fn key(zoom_level: u8, x: u32, y: u32) -> TileKey {
+        TileKey { zoom_level, x, y }
+    }
+
+    #[test]
+    fn tile_ids_follow_the_hilbert_curve() {
+        assert_eq!(tile_id(key(0, 0, 0)), 0);
+        assert_eq!(tile_id(key(1, 0, 0)), 1);
+        assert_eq!(tile_id(key(1, 0, 1)), 2);
+        assert_eq!(tile_id(key(1, 1, 1)), 3);
+        assert_eq!(tile_id(key(1, 1, 0)), 4);
+        assert_eq!(tile_id(key(2, 0, 0)), 5);
+        assert_eq!(tile_id(key(2, 0, 3)), 10);
+        assert_eq!(tile_id(key(2, 3, 3)), 15);
+        assert_eq!(tile_id(key(2, 3, 0)), 20);
+        assert_eq!(tile_id(key(3, 0, 0)), 21);
+        assert_eq!(tile_id(key(12, 3423, 1763)), 19_078_479);
+        assert_eq!(tile_id(key(20, 0, 0)), 366_503_875_925);
+    }
+
+    #[test]
+    fn read_varint_decodes_and_rejects_invalid_input() {
+        let mut bytes: &[u8] = &[0x05, 0xac, 0x02, 0x7f];
+        assert_eq!(read_varint(&mut bytes).unwrap(), 5);
+        assert_eq!(read_varint(&mut bytes).unwrap(), 300);
+        assert_eq!(bytes, [0x7f]);
+
+        let mut max = [0xff; 10];
+        max[9] = 0x01;
+        assert_eq!(read_varint(&mut &max[..]).unwrap(), u64::MAX);
+        // Bits beyond 64, and more than ten bytes.
+        max[9] = 0x02;
+        assert!(read_varint(&mut &max[..]).is_err());
+        assert!(read_varint(&mut &[0xff; 11][..]).is_err());
+        // Truncated input.
+        assert!(read_varint(&mut &[0x80][..]).is_err());
+        assert!(read_varint(&mut &[][..]).is_err());
+    }
+
+    #[test]
+    fn parse_directory_decodes_deltas_runs_and_offsets() {
+        let entries = [(5, 1, 100, 0), (6, 2, 50, 100), (10, 0, 20, 1000)];
+        let parsed = parse_directory(&encode_directory(&entries)).unwrap();
+        let parsed: Vec<_> = parsed
+            .iter()
+            .map(|entry| (entry.tile_id, entry.run_length, entry.length, entry.offset))
+            .collect();
+        assert_eq!(parsed, entries);
+
+        let mut truncated = encode_directory(&entries);
+        truncated.pop();
+        assert!(parse_directory(&truncated).is_err());
+        // The first offset cannot refer to a previous entry.
+        assert!(parse_directory(&[1, 5, 1, 100, 0]).is_err());
+    }
+
+    #[test]
+    fn find_entry_follows_runs_and_leaf_directories() {
+        let entries = parse_directory(&encode_directory(&[
+            (5, 1, 100, 0),
+            (6, 2, 50, 100),
+            (10, 0, 20, 1000),
+        ]))
+        .unwrap();
+        let found = |tile_id| find_entry(&entries, tile_id).map(|entry| entry.tile_id);
+        assert_eq!(found(4), None);
+        assert_eq!(found(5), Some(5));
+        // Tiles 6 and 7 share the data of the run.
+        assert_eq!(found(6), Some(6));
+        assert_eq!(found(7), Some(6));
+        assert_eq!(found(8), None);
+        assert_eq!(found(9), None);
+        // The leaf directory covers every tile id from its own up to the next entry.
+        assert_eq!(found(10), Some(10));
+        assert_eq!(found(1_000_000), Some(10));
+    }
+
+    #[test]
+    fn header_parse_rejects_other_files() {
+        let header = Header::parse(&header_bytes()).unwrap();
+        assert_eq!(header.internal_compression, Compression::Gzip);
+        assert_eq!(header.tile_compression, Compression::None);
+
+        let mut magic = header_bytes();
+        magic[0] = b'X';
+        assert!(matches!(
+            Header::parse(&magic),
+            Err(PmTilesError::InvalidHeader(_))
+        ));
+        assert!(matches!(
+            Header::parse(&header_with(2, 2)),
+            Err(PmTilesError::InvalidHeader(_))
+        ));
+        assert!(matches!(
+            Header::parse(&header_bytes()[..HEADER_LENGTH - 1]),
+            Err(PmTilesError::InvalidHeader(_))
+        ));
+        // Vector tiles (1) are not displayed.
+        assert!(matches!(
+            Header::parse(&header_with(VERSION, 1)),
+            Err(PmTilesError::UnsupportedTileType(1))
+        ));
+    }
+
+    #[test]
+    fn read_at_rejects_ranges_beyond_the_archive() {
+        let mut file = Cursor::new(vec![7; 16]);
+        assert_eq!(read_at(&mut file, 16, 12, 4).unwrap(), [7; 4]);
+        assert!(matches!(
+            read_at(&mut file, 16, 12, 5),
+            Err(PmTilesError::InvalidRange(12, 5))
+        ));
+        assert!(read_at(&mut file, 16, u64::MAX, 2).is_err());
+        assert!(read_at(&mut file, u64::MAX, 0, MAX_READ_LENGTH + 1).is_err());
+    }
+
+    #[test]
+    fn tiles_are_read_through_compressed_leaf_directories() {
+        // Tiles 1 and 2 share their data, tile 3 is missing, tile 4 has its own.
+        let tile_data = b"abcdxyz";
+        let leaf = gzip(&encode_directory(&[(1, 2, 4, 0), (4, 1, 3, 4)]));
+        let root = gzip(&encode_directory(&[(0, 0, leaf.len() as u64, 0)]));
+        let metadata = gzip(br#"{"attribution": "Test data"}"#);
+
+        let mut archive = header_bytes();
+        let section = |offset: usize, bytes: &[u8], archive: &mut Vec<u8>| {
+            let start = archive.len() as u64;
+            archive[offset..offset + 8].copy_from_slice(&start.to_le_bytes());
+            archive[offset + 8..offset + 16].copy_from_slice(&(bytes.len() as u64).to_le_bytes());
+            archive.extend_from_slice(bytes);
+        };
+        section(8, &root, &mut archive);
+        section(24, &metadata, &mut archive);
+        section(40, &leaf, &mut archive);
+        section(56, tile_data, &mut archive);
+        archive[100] = 0;
+        archive[101] = 1;
+
+        let path = std::env::temp_dir().join(format!("mapapp-test-{}.pmtiles", std::process::id()));
+        std::fs::write(&path, &archive).unwrap();
+        let pmtiles = PmTiles::open(&path);
+        std::fs::remove_file(&path).unwrap();
+        let pmtiles = pmtiles.unwrap();
+
+        assert_eq!(pmtiles.metadata().attribution.as_deref(), Some("Test data"));
+        assert_eq!(pmtiles.metadata().max_zoom, 1);
+        let read = |zoom_level, x, y| pmtiles.read_tile(key(zoom_level, x, y)).unwrap();
+        assert_eq!(read(1, 0, 0).as_deref(), Some(&b"abcd"[..]));
+        assert_eq!(read(1, 0, 1).as_deref(), Some(&b"abcd"[..]));
+        assert_eq!(read(1, 1, 1), None);
+        assert_eq!(read(1, 1, 0).as_deref(), Some(&b"xyz"[..]));
+        assert_eq!(read(0, 0, 0), None);
+    }
+}
@@ -0,0 +1,318 @@
+// Web Mercator (EPSG:3857) conversions between latitude/longitude, projected meters,
+// slippy tile indices and Bevy world coordinates.
+//
+// The Bevy world uses projected meters, x grows to the east, y grows to the north and one world unit
+// is one meter at the equator. They are relative to a floating origin kept near the camera: f32
+// coordinates are a meter apart thousands of kilometers away from latitude/longitude (0, 0), several
+// pixels at the highest zoom levels. Positions kept across frames are stored in projected meters.
+// Slippy tiles count x to the east and y to the south from the north-west corner of the world.
+
+use std::f64::consts::PI;
+
+use bevy::math::{DVec2, URect, Vec2};
+use bevy::prelude::Resource;
+
+use crate::config::TileSize;
+
+pub const EARTH_RADIUS: f64 = 6_378_137.0;
+// Latitude at which the Web Mercator square ends.
+pub const MAX_LATITUDE: f64 = 85.051_128_779_806_59;
+// Distance from the origin to the edge of the world in projected meters.
+pub const HALF_WORLD: f64 = PI * EARTH_RADIUS;
+pub const WORLD_SIZE: f32 = (2.0 * HALF_WORLD) as f32;
+pub const TILE_PIXELS: f32 = 256.0;
+pub const MIN_ZOOM_LEVEL: u8 = 0;
+pub const MAX_ZOOM_LEVEL: u8 = 19;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LatLon {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+impl LatLon {
+    pub fn new(latitude: f64, longitude: f64) -> Self {
+        Self {
+            latitude,
+            longitude,
+        }
+    }
+}
+
+pub fn lat_lon_to_meters(lat_lon: LatLon) -> DVec2 {
+    let latitude = lat_lon.latitude.clamp(-MAX_LATITUDE, MAX_LATITUDE);
+    let x = lat_lon.longitude.to_radians() * EARTH_RADIUS;
+    let y = (PI / 4.0 + latitude.to_radians() / 2.0).tan().ln() * EARTH_RADIUS;
+    DVec2::new(x, y)
+}
+
+pub fn meters_to_lat_lon(meters: DVec2) -> LatLon {
+    let longitude = (meters.x / EARTH_RADIUS).to_degrees();
+    let latitude = (2.0 * (meters.y / EARTH_RADIUS).exp().atan() - PI / 2.0).to_degrees();
+    LatLon::new(latitude, longitude)
+}
+
+// Projected meters of the Bevy world origin, moved under the camera by `recenter_world`.
+#[derive(Resource, Clone, Copy, Debug, Default, PartialEq)]
+pub struct WorldOrigin(pub DVec2);
+
+impl WorldOrigin {
+    pub fn meters_to_world(&self, meters: DVec2) -> Vec2 {
+        (meters - self.0).as_vec2()
+    }
+
+    pub fn world_to_meters(&self, point: Vec2) -> DVec2 {
+        point.as_dvec2() + self.0
+    }
+
+    pub fn lat_lon_to_world(&self, lat_lon: LatLon) -> Vec2 {
+        self.meters_to_world(lat_lon_to_meters(lat_lon))
+    }
+
+    pub fn world_to_lat_lon(&self, point: Vec2) -> LatLon {
+        meters_to_lat_lon(self.world_to_meters(point))
+    }
+
+    // World position of the center of a slippy tile.
+    pub fn tile_to_world(&self, x: u32, y: u32, zoom_level: u8) -> Vec2 {
+        self.meters_to_world(tile_center(x, y, zoom_level))
+    }
+
+    // Slippy tile containing the world point at the given zoom level, clamped to the world.
+    pub fn world_to_tile(&self, point: Vec2, zoom_level: u8) -> (u32, u32) {
+        meters_to_tile_index(self.world_to_meters(point), zoom_level)
+    }
+}
+
+// Meters on the ground covered by a pixel at the latitude, for a projection scale in world units
+// per pixel. Web Mercator stretches distances by 1 / cos(latitude).
+pub fn ground_resolution(scale: f32, latitude: f64) -> f64 {
+    scale as f64 * latitude.to_radians().cos()
+}
+
+// Distance in meters on the ground between two locations, on a sphere of the Earth's radius.
+pub fn great_circle_distance(a: LatLon, b: LatLon) -> f64 {
+    let (latitude_a, latitude_b) = (a.latitude.to_radians(), b.latitude.to_radians());
+    let half_latitude = (latitude_b - latitude_a) / 2.0;
+    let half_longitude = (b.longitude - a.longitude).to_radians() / 2.0;
+    let h = half_latitude.sin().powi(2)
+        + latitude_a.cos() * latitude_b.cos() * half_longitude.sin().powi(2);
+    2.0 * EARTH_RADIUS * h.sqrt().min(1.0).asin()
+}
+
+// Size of a single tile in projected meters at the given zoom level.
+pub fn tile_size_meters(zoom_level: u8) -> f64 {
+    2.0 * HALF_WORLD / 2_f64.powi(zoom_level as i32)
+}
+
+// Size of a single tile in world units at the given zoom level.
+pub fn tile_world_size(zoom_level: u8) -> f32 {
+    tile_size_meters(zoom_level) as f32
+}
+
+// Fractional slippy tile position of a point in projected meters.
+pub fn meters_to_tile(meters: DVec2, zoom_level: u8) -> DVec2 {
+    let size = tile_size_meters(zoom_level);
+    DVec2::new(
+        (meters.x + HALF_WORLD) / size,
+        (HALF_WORLD - meters.y) / size,
+    )
+}
+
+// Projected meters of a fractional slippy tile position.
+pub fn tile_to_meters(tile: DVec2, zoom_level: u8) -> DVec2 {
+    let size = tile_size_meters(zoom_level);
+    DVec2::new(tile.x * size - HALF_WORLD, HALF_WORLD - tile.y * size)
+}
+
+// Slippy tile containing the point in projected meters at the given zoom level, clamped to the world.
+pub fn meters_to_tile_index(meters: DVec2, zoom_level: u8) -> (u32, u32) {
+    let last = (2_u32.pow(zoom_level as u32) - 1) as f64;
+    let tile = meters_to_tile(meters, zoom_level).floor();
+    (
+        tile.x.clamp(0.0, last) as u32,
+        tile.y.clamp(0.0, last) as u32,
+    )
+}
+
+// Range of tiles at the zoom level covering the bounds, given as west, south, east, north in degrees.
+pub fn bounds_to_tiles(bounds: [f64; 4], zoom_level: u8) -> URect {
+    let [west, south, east, north] = bounds;
+    let last = (2_u32.pow(zoom_level as u32) - 1) as f64;
+    let north_west = meters_to_tile(lat_lon_to_meters(LatLon::new(north, west)), zoom_level);
+    let south_east = meters_to_tile(lat_lon_to_meters(LatLon::new(south, east)), zoom_level);
+    let clamp = |value: f64| value.floor().clamp(0.0, last) as u32;
+    URect::new(
+        clamp(north_west.x),
+        clamp(north_west.y),
+        clamp(south_east.x),
+        clamp(south_east.y),
+    )
+}
+
+// Projected meters of the center of a slippy tile.
+pub fn tile_center(x: u32, y: u32, zoom_level: u8) -> DVec2 {
+    tile_to_meters(DVec2::new(x as f64 + 0.5, y as f64 + 0.5), zoom_level)
+}
+
+// Projection scale at which 256 px tiles of the given zoom level are displayed pixel for pixel.
+// Zoom levels of the camera, the flights and the configuration are counted in these tiles.
+pub fn zoom_level_to_scale(zoom_level: u8) -> f32 {
+    tile_world_size(zoom_level) / TILE_PIXELS
+}
+
+// Closest slippy zoom level for the projection scale.
+pub fn scale_to_zoom_level(scale: f32) -> u8 {
+    let zoom = (WORLD_SIZE / (TILE_PIXELS * scale)).log2().round();
+    zoom.clamp(MIN_ZOOM_LEVEL as f32, MAX_ZOOM_LEVEL as f32) as u8
+}
+
+// Zoom level of the tiles of the given size displayed at the projection scale. Large tiles cover
+// the area of a 256 px tile in more pixels, they are taken one zoom level lower for every doubling
+// so each tile pixel still covers about one screen pixel.
+pub fn tile_zoom_level(scale: f32, tile_size: TileSize) -> u8 {
+    let levels = (tile_size.to_pixels() as f32 / TILE_PIXELS).log2().round() as u8;
+    scale_to_zoom_level(scale).saturating_sub(levels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HELSINKI: LatLon = LatLon {
+        latitude: 60.1699,
+        longitude: 24.9384,
+    };
+
+    fn assert_close(a: LatLon, b: LatLon, tolerance: f64) {
+        assert!(
+            (a.latitude - b.latitude).abs() < tolerance
+                && (a.longitude - b.longitude).abs() < tolerance,
+            "{:?} != {:?}",
+            a,
+            b
+        );
+    }
+
+    #[test]
+    fn lat_lon_meters_round_trip() {
+        for lat_lon in [
+            LatLon::new(0.0, 0.0),
+            HELSINKI,
+            LatLon::new(-33.8688, 151.2093),
+            LatLon::new(84.9, -179.9),
+        ] {
+            assert_close(meters_to_lat_lon(lat_lon_to_meters(lat_lon)), lat_lon, 1e-9);
+        }
+        assert!(lat_lon_to_meters(LatLon::new(0.0, 0.0)).length() < 1e-6);
+    }
+
+    #[test]
+    fn meters_tile_round_trip() {
+        let meters = lat_lon_to_meters(HELSINKI);
+        for zoom_level in MIN_ZOOM_LEVEL..=MAX_ZOOM_LEVEL {
+            let tile = meters_to_tile(meters, zoom_level);
+            assert!((tile_to_meters(tile, zoom_level) - meters).length() < 1e-6);
+            let (x, y) = meters_to_tile_index(meters, zoom_level);
+            assert_eq!((x, y), (tile.x as u32, tile.y as u32));
+            // The tile center is within half a tile of the point.
+            let offset = tile_center(x, y, zoom_level) - meters;
+            assert!(offset.abs().max_element() <= tile_size_meters(zoom_level) / 2.0);
+        }
+        // Tile 1/1/0 at zoom level 1 is the north-east quarter of the world.
+        let center = tile_center(1, 0, 1);
+        assert!((center - DVec2::splat(HALF_WORLD / 2.0)).length() < 1e-6);
+    }
+
+    #[test]
+    fn world_round_trip_keeps_precision_away_from_the_equator() {
+        // A pixel at the highest zoom level is about 0.3 projected meters.
+        let pixel = zoom_level_to_scale(MAX_ZOOM_LEVEL) as f64;
+        let origin = WorldOrigin(lat_lon_to_meters(HELSINKI));
+        let meters = lat_lon_to_meters(HELSINKI) + DVec2::new(1234.56, -789.01);
+        let round_trip = origin.world_to_meters(origin.meters_to_world(meters));
+        assert!((round_trip - meters).length() < pixel / 100.0);
+        assert_close(
+            origin.world_to_lat_lon(origin.meters_to_world(lat_lon_to_meters(HELSINKI))),
+            HELSINKI,
+            1e-9,
+        );
+
+        let (x, y) = meters_to_tile_index(meters, MAX_ZOOM_LEVEL);
+        let center = origin.tile_to_world(x, y, MAX_ZOOM_LEVEL);
+        assert_eq!(origin.world_to_tile(center, MAX_ZOOM_LEVEL), (x, y));
+    }
+
+    #[test]
+    fn poles_and_antimeridian_are_clamped() {
+        let north = lat_lon_to_meters(LatLon::new(90.0, 0.0));
+        let south = lat_lon_to_meters(LatLon::new(-90.0, 0.0));
+        assert!((north.y - HALF_WORLD).abs() < 1e-6);
+        assert!((south.y + HALF_WORLD).abs() < 1e-6);
+        assert!((meters_to_lat_lon(north).latitude - MAX_LATITUDE).abs() < 1e-9);
+
+        let east = lat_lon_to_meters(LatLon::new(0.0, 180.0));
+        let west = lat_lon_to_meters(LatLon::new(0.0, -180.0));
+        assert!((east.x - HALF_WORLD).abs() < 1e-6);
+        assert!((west.x + HALF_WORLD).abs() < 1e-6);
+
+        // Points on or beyond the edges belong to the edge tiles.
+        for zoom_level in [0, 1, 10, MAX_ZOOM_LEVEL] {
+            let last = 2_u32.pow(zoom_level as u32) - 1;
+            // The equator and the prime meridian are the top and left edges of the second half.
+            let half = 2_u32.pow(zoom_level as u32) / 2;
+            assert_eq!(meters_to_tile_index(north, zoom_level), (half, 0));
+            assert_eq!(meters_to_tile_index(west, zoom_level), (0, half));
+            assert_eq!(meters_to_tile_index(east, zoom_level).0, last);
+            assert_eq!(meters_to_tile_index(south, zoom_level).1, last);
+            assert_eq!(
+                meters_to_tile_index(DVec2::splat(4.0 * HALF_WORLD), zoom_level),
+                (last, 0)
+            );
+            let all = bounds_to_tiles([-180.0, -90.0, 180.0, 90.0], zoom_level);
+            assert_eq!(all, URect::new(0, 0, last, last));
+        }
+    }
+
+    #[test]
+    fn scale_to_zoom_level_inverts_zoom_level_to_scale() {
+        for zoom_level in MIN_ZOOM_LEVEL..=MAX_ZOOM_LEVEL {
+            let scale = zoom_level_to_scale(zoom_level);
+            assert_eq!(scale_to_zoom_level(scale), zoom_level);
+            // Scales between two levels round to the closest one.
+            assert_eq!(scale_to_zoom_level(scale * 1.2), zoom_level);
+            assert_eq!(
+                scale_to_zoom_level(scale * 1.6),
+                zoom_level.saturating_sub(1)
+            );
+            assert_eq!(
+                scale_to_zoom_level(scale / 1.6),
+                (zoom_level + 1).min(MAX_ZOOM_LEVEL)
+            );
+        }
+        assert_eq!(
+            scale_to_zoom_level(zoom_level_to_scale(0) * 4.0),
+            MIN_ZOOM_LEVEL
+        );
+        assert_eq!(
+            scale_to_zoom_level(zoom_level_to_scale(MAX_ZOOM_LEVEL) / 4.0),
+            MAX_ZOOM_LEVEL
+        );
+    }
+
+    #[test]
+    fn large_tiles_are_taken_one_zoom_level_lower() {
+        for zoom_level in MIN_ZOOM_LEVEL..=MAX_ZOOM_LEVEL {
+            let scale = zoom_level_to_scale(zoom_level);
+            assert_eq!(tile_zoom_level(scale, TileSize::Normal), zoom_level);
+            assert_eq!(
+                tile_zoom_level(scale, TileSize::Large),
+                zoom_level.saturating_sub(1)
+            );
+        }
+        // A 512 px tile at the tile zoom level is displayed at 512 px.
+        let scale = zoom_level_to_scale(12);
+        let tile_pixels = tile_world_size(tile_zoom_level(scale, TileSize::Large)) / scale;
+        assert_eq!(tile_pixels, 512.0);
+    }
+}
@@ -0,0 +1,188 @@
+// Cursor readout in the top right corner: the latitude and longitude under the cursor in decimal
+// degrees and degrees, minutes and seconds, and the slippy tile at the displayed zoom level.
+// It shows the last position over the map while the cursor is over the interface, clicking it
+// copies the decimal coordinates to the clipboard.
+
+use bevy::math::DVec2;
+use bevy::prelude::*;
+
+use crate::projection::{meters_to_lat_lon, meters_to_tile_index, WorldOrigin, HALF_WORLD};
+use crate::{MainCamera, WorldState};
+
+// Seconds the copy confirmation is shown.
+const COPIED_DURATION: f32 = 1.5;
+
+#[derive(Component)]
+pub struct ReadoutButton;
+
+#[derive(Component)]
+pub struct ReadoutText;
+
+#[derive(Resource, Default)]
+pub struct CursorReadout {
+    // Projected meters under the cursor, or the last ones while the cursor is over the interface.
+    point: Option<DVec2>,
+    // Seconds since startup the coordinates were copied.
+    copied_at: Option<f32>,
+}
+
+// Clipboard the coordinates are copied to, opened on the first copy. It stays open: on Linux the
+// copied text is served by the clipboard owner and lost once it is dropped.
+#[derive(Default)]
+pub struct ReadoutClipboard(Option<arboard::Clipboard>);
+
+pub fn setup_readout(mut commands: Commands) {
+    commands.init_resource::<CursorReadout>();
+    commands
+        .spawn((
+            ReadoutButton,
+            ButtonBundle {
+                style: Style {
+                    position_type: PositionType::Absolute,
+                    right: Val::Px(8.0),
+                    top: Val::Px(8.0),
+                    padding: UiRect::all(Val::Px(6.0)),
+                    ..default()
+                },
+                background_color: Color::srgba(1.0, 1.0, 1.0, 0.8).into(),
+                visibility: Visibility::Hidden,
+                ..default()
+            },
+        ))
+        .with_children(|parent| {
+            parent.spawn((
+                ReadoutText,
+                TextBundle::from_section(
+                    "",
+                    TextStyle {
+                        font_size: 14.0,
+                        color: Color::BLACK,
+                        ..default()
+                    },
+                ),
+            ));
+        });
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn update_readout(
+    cameras: Query<(&Camera, &GlobalTransform), With<MainCamera>>,
+    windows: Query<&Window>,
+    interactions: Query<&Interaction>,
+    clicks: Query<&Interaction, (Changed<Interaction>, With<ReadoutButton>)>,
+    mut buttons: Query<&mut Visibility, With<ReadoutButton>>,
+    mut texts: Query<&mut Text, With<ReadoutText>>,
+    time: Res<Time>,
+    origin: Res<WorldOrigin>,
+    state: Res<WorldState>,
+    mut readout: ResMut<CursorReadout>,
+    mut clipboard: NonSendMut<ReadoutClipboard>,
+) {
+    let (camera, camera_transform) = cameras.single();
+    let over_interface = interactions
+        .iter()
+        .any(|interaction| *interaction != Interaction::None);
+    let point = windows
+        .single()
+        .cursor_position()
+        .and_then(|cursor| camera.viewport_to_world_2d(camera_transform, cursor))
+        .map(|point| origin.world_to_meters(point))
+        .filter(|point| point.abs().max_element() <= HALF_WORLD);
+
+    // The readout follows the cursor over the map and keeps the last position over the interface.
+    if !over_interface {
+        readout.point = point;
+    }
+    *buttons.single_mut() = if readout.point.is_some() {
+        Visibility::Inherited
+    } else {
+        Visibility::Hidden
+    };
+    let Some(point) = readout.point else {
+        return;
+    };
+    let lat_lon = meters_to_lat_lon(point);
+
+    if clicks
+        .iter()
+        .any(|interaction| *interaction == Interaction::Pressed)
+    {
+        let coordinates = format!("{:.6}, {:.6}", lat_lon.latitude, lat_lon.longitude);
+        if clipboard.0.is_none() {
+            clipboard.0 = arboard::Clipboard::new()
+                .inspect_err(|err| warn!("Cannot open the clipboard: {}", err))
+                .ok();
+        }
+        if let Some(clipboard) = &mut clipboard.0 {
+            match clipboard.set_text(coordinates) {
+                Ok(()) => readout.copied_at = Some(time.elapsed_seconds()),
+                Err(err) => warn!("Cannot copy the coordinates: {}", err),
+            }
+        }
+    }
+
+    let mut lines = vec![
+        format!("{:.6}°, {:.6}°", lat_lon.latitude, lat_lon.longitude),
+        format!(
+            "{} {}",
+            dms(lat_lon.latitude, 'N', 'S'),
+            dms(lat_lon.longitude, 'E', 'W')
+        ),
+    ];
+    if let Some(zoom_level) = state.zoom_level {
+        let (x, y) = meters_to_tile_index(point, zoom_level);
+        lines.push(format!("tile {}/{}/{}", zoom_level, x, y));
+    }
+    if readout
+        .copied_at
+        .is_some_and(|copied_at| time.elapsed_seconds() - copied_at < COPIED_DURATION)
+    {
+        lines.push("Copied to the clipboard".into());
+    }
+    let value = lines.join("\n");
+    let mut text = texts.single_mut();
+    if text.sections[0].value != value {
+        text.sections[0].value = value;
+    }
+}
+
+// Degrees, minutes and seconds to a tenth of a second, with the hemisphere letter.
+fn dms(degrees: f64, positive: char, negative: char) -> String {
+    let tenths = (degrees.abs() * 36_000.0).round() as u64;
+    // Values rounding to zero are on the equator or prime meridian, not south or west of them.
+    let hemisphere = if degrees < 0.0 && tenths > 0 {
+        negative
+    } else {
+        positive
+    };
+    format!(
+        "{}°{:02}′{:04.1}″{}",
+        tenths / 36_000,
+        tenths / 600 % 60,
+        (tenths % 600) as f64 / 10.0,
+        hemisphere
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dms_uses_the_hemisphere_letters() {
+        assert_eq!(dms(60.1699, 'N', 'S'), "60°10′11.6″N");
+        assert_eq!(dms(-24.9384, 'E', 'W'), "24°56′18.2″W");
+        assert_eq!(dms(-33.8688, 'N', 'S'), "33°52′07.7″S");
+        assert_eq!(dms(0.0, 'N', 'S'), "0°00′00.0″N");
+        assert_eq!(dms(-0.00001, 'E', 'W'), "0°00′00.0″E");
+        assert_eq!(dms(-180.0, 'E', 'W'), "180°00′00.0″W");
+    }
+
+    #[test]
+    fn dms_carries_rounded_seconds_into_minutes_and_degrees() {
+        // 59.99976″ and 59.9964″ round up to the next minute and degree.
+        assert_eq!(dms(0.0166666, 'N', 'S'), "0°01′00.0″N");
+        assert_eq!(dms(-10.99999, 'E', 'W'), "11°00′00.0″W");
+        assert_eq!(dms(10.99, 'E', 'W'), "10°59′24.0″E");
+    }
+}
@@ -0,0 +1,193 @@
+// Scale bar in the bottom left corner: the on-screen length of a round distance on the ground at
+// the latitude of the viewport center, in metric or imperial units.
+
+use bevy::prelude::*;
+
+use crate::config::{MapConfig, Units};
+use crate::projection::{ground_resolution, WorldOrigin};
+use crate::MainCamera;
+
+// The bar shows the largest round distance fitting in this many pixels.
+const MAX_BAR_WIDTH: f64 = 120.0;
+const METERS_PER_KILOMETER: f64 = 1000.0;
+const METERS_PER_FOOT: f64 = 0.3048;
+const FEET_PER_MILE: f64 = 5280.0;
+
+#[derive(Component)]
+pub struct ScaleBar;
+
+#[derive(Component)]
+pub struct ScaleLabel;
+
+pub fn setup_scale_bar(mut commands: Commands) {
+    commands
+        .spawn(NodeBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                left: Val::Px(8.0),
+                bottom: Val::Px(8.0),
+                flex_direction: FlexDirection::Column,
+                align_items: AlignItems::FlexStart,
+                ..default()
+            },
+            ..default()
+        })
+        .with_children(|parent| {
+            parent.spawn((
+                ScaleLabel,
+                TextBundle::from_section(
+                    "",
+                    TextStyle {
+                        font_size: 14.0,
+                        color: Color::BLACK,
+                        ..default()
+                    },
+                ),
+            ));
+            // Open bracket shape: a thin bar with ticks at both ends.
+            parent.spawn((
+                ScaleBar,
+                NodeBundle {
+                    style: Style {
+                        width: Val::Px(0.0),
+                        height: Val::Px(6.0),
+                        border: UiRect {
+                            left: Val::Px(2.0),
+                            right: Val::Px(2.0),
+                            bottom: Val::Px(2.0),
+                            top: Val::Px(0.0),
+                        },
+                        ..default()
+                    },
+                    border_color: Color::BLACK.into(),
+                    background_color: Color::srgba(1.0, 1.0, 1.0, 0.7).into(),
+                    ..default()
+                },
+            ));
+        });
+}
+
+pub fn update_scale_bar(
+    cameras: Query<(&Transform, &OrthographicProjection), With<MainCamera>>,
+    origin: Res<WorldOrigin>,
+    config: Res<MapConfig>,
+    mut bars: Query<&mut Style, With<ScaleBar>>,
+    mut labels: Query<&mut Text, With<ScaleLabel>>,
+) {
+    let (camera, projection) = cameras.single();
+    let latitude = origin
+        .world_to_lat_lon(camera.translation.truncate())
+        .latitude;
+    let meters_per_pixel = ground_resolution(projection.scale, latitude);
+    let (meters, label) = scale_distance(meters_per_pixel * MAX_BAR_WIDTH, config.units);
+
+    bars.single_mut().width = Val::Px((meters / meters_per_pixel) as f32);
+    let mut text = labels.single_mut();
+    if text.sections[0].value != label {
+        text.sections[0].value = label;
+    }
+}
+
+// Distance as displayed in the units, to a tenth of the larger units.
+pub fn format_distance(meters: f64, units: Units) -> String {
+    match units {
+        Units::Metric if meters >= METERS_PER_KILOMETER => {
+            format!("{:.1} km", meters / METERS_PER_KILOMETER)
+        }
+        Units::Metric => format!("{:.0} m", meters),
+        Units::Imperial if meters / METERS_PER_FOOT >= FEET_PER_MILE => {
+            format!("{:.1} mi", meters / METERS_PER_FOOT / FEET_PER_MILE)
+        }
+        Units::Imperial => format!("{:.0} ft", meters / METERS_PER_FOOT),
+    }
+}
+
+// Largest round distance up to `max_meters`, in meters and as displayed.
+fn scale_distance(max_meters: f64, units: Units) -> (f64, String) {
+    match units {
+        Units::Metric if max_meters >= METERS_PER_KILOMETER => {
+            let kilometers = round_down(max_meters / METERS_PER_KILOMETER);
+            (
+                kilometers * METERS_PER_KILOMETER,
+                format!("{} km", kilometers),
+            )
+        }
+        Units::Metric => {
+            let meters = round_down(max_meters);
+            (meters, format!("{} m", meters))
+        }
+        Units::Imperial if max_meters / METERS_PER_FOOT >= FEET_PER_MILE => {
+            let miles = round_down(max_meters / METERS_PER_FOOT / FEET_PER_MILE);
+            (
+                miles * FEET_PER_MILE * METERS_PER_FOOT,
+                format!("{} mi", miles),
+            )
+        }
+        Units::Imperial => {
+            let feet = round_down(max_meters / METERS_PER_FOOT);
+            (feet * METERS_PER_FOOT, format!("{} ft", feet))
+        }
+    }
+}
+
+// Largest 1, 2 or 5 times a power of ten up to the value.
+fn round_down(value: f64) -> f64 {
+    let magnitude = 10_f64.powf(value.log10().floor());
+    let leading = value / magnitude;
+    let round = if leading >= 5.0 {
+        5.0
+    } else if leading >= 2.0 {
+        2.0
+    } else {
+        1.0
+    };
+    round * magnitude
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_scale(max_meters: f64, units: Units, meters: f64, label: &str) {
+        let (distance, text) = scale_distance(max_meters, units);
+        assert!(
+            (distance - meters).abs() < 1e-6,
+            "{} != {}",
+            distance,
+            meters
+        );
+        assert_eq!(text, label);
+    }
+
+    #[test]
+    fn round_down_picks_one_two_or_five() {
+        assert_eq!(round_down(1.0), 1.0);
+        assert_eq!(round_down(1.9), 1.0);
+        assert_eq!(round_down(2.0), 2.0);
+        assert_eq!(round_down(4.99), 2.0);
+        assert_eq!(round_down(5.0), 5.0);
+        assert_eq!(round_down(9.99), 5.0);
+        assert_eq!(round_down(73.0), 50.0);
+        assert_eq!(round_down(240.0), 200.0);
+        assert_eq!(round_down(1000.0), 1000.0);
+        assert!((round_down(0.7) - 0.5).abs() < 1e-12);
+    }
+
+    #[test]
+    fn metric_scales_switch_to_kilometers() {
+        assert_scale(73.0, Units::Metric, 50.0, "50 m");
+        assert_scale(999.0, Units::Metric, 500.0, "500 m");
+        assert_scale(1000.0, Units::Metric, 1000.0, "1 km");
+        assert_scale(4200.0, Units::Metric, 2000.0, "2 km");
+        assert_scale(640_000.0, Units::Metric, 500_000.0, "500 km");
+    }
+
+    #[test]
+    fn imperial_scales_switch_to_miles_at_a_mile() {
+        assert_scale(100.0, Units::Imperial, 200.0 * METERS_PER_FOOT, "200 ft");
+        // 1600 m are 5249 ft, just short of a mile.
+        assert_scale(1600.0, Units::Imperial, 5000.0 * METERS_PER_FOOT, "5000 ft");
+        assert_scale(1610.0, Units::Imperial, 1609.344, "1 mi");
+        assert_scale(10_000.0, Units::Imperial, 5.0 * 1609.344, "5 mi");
+    }
+}
@@ -0,0 +1,234 @@
+// Loading and error indicators: a checkerboard is drawn under every requested tile until it
+// arrives, tinted red when the tile failed. Failed downloads are requested again with exponential
+// backoff.
+//
+// A download fails once it finishes with an error, slow downloads queued behind others on the
+// download pool are waited for, so a tile is never downloaded twice at the same time. Downloaded
+// tiles whose image cannot be decoded are deleted from the cache and failed too. Archive tiles that
+// cannot be read are reported with `TileFailed` and not retried, reading them again gives the same
+// result.
+
+use bevy::asset::LoadState;
+use bevy::prelude::*;
+use bevy::render::render_asset::RenderAssetUsages;
+use bevy::render::render_resource::{Extent3d, TextureDimension, TextureFormat};
+use bevy::render::texture::ImageSampler;
+use bevy::utils::{HashMap, HashSet};
+
+use crate::cache::TileCache;
+use crate::config::MapConfig;
+use crate::layers::ActiveBaseLayer;
+use crate::offline::OfflineSources;
+use crate::projection::{tile_world_size, WorldOrigin};
+use crate::{
+    download_tile, DownloadPool, MapTile, RequestedTiles, TileDownload, TileIndex, TileKey,
+};
+
+// Seconds before the first retry, doubled after every failed attempt up to `MAX_RETRY_DELAY`.
+const RETRY_DELAY: f32 = 1.0;
+const MAX_RETRY_DELAY: f32 = 60.0;
+// Attempts per tile, including the first request, before the tile stays failed.
+const MAX_ATTEMPTS: u32 = 6;
+// Indicators are drawn under the placeholders of their tile.
+const INDICATOR_Z_OFFSET: f32 = -0.75;
+const ERROR_TINT: Color = Color::srgb(1.0, 0.45, 0.45);
+// Checkerboard texture, in pixels.
+const CHECKERBOARD_SIZE: u32 = 64;
+const CHECKERBOARD_CELL: u32 = 8;
+
+// A tile of the active base layer failed to download or cannot be read.
+#[derive(Event)]
+pub struct TileFailed(pub TileKey);
+
+struct TileStatus {
+    // Base layer the tile was requested for.
+    layer: usize,
+    indicator: Entity,
+    attempts: u32,
+    // Seconds since startup of the next request, once the last one failed.
+    retry_at: Option<f32>,
+}
+
+#[derive(Resource)]
+pub struct TileStatuses {
+    checkerboard: Handle<Image>,
+    // Statuses of the tiles requested and not arrived yet.
+    pending: HashMap<TileKey, TileStatus>,
+}
+
+pub fn setup_tile_status(mut commands: Commands, mut images: ResMut<Assets<Image>>) {
+    let data = (0..CHECKERBOARD_SIZE * CHECKERBOARD_SIZE)
+        .flat_map(|pixel| {
+            let x = pixel % CHECKERBOARD_SIZE / CHECKERBOARD_CELL;
+            let y = pixel / CHECKERBOARD_SIZE / CHECKERBOARD_CELL;
+            let value = if (x + y).is_multiple_of(2) { 224 } else { 192 };
+            [value, value, value, 255]
+        })
+        .collect();
+    let mut checkerboard = Image::new(
+        Extent3d {
+            width: CHECKERBOARD_SIZE,
+            height: CHECKERBOARD_SIZE,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        data,
+        TextureFormat::Rgba8UnormSrgb,
+        RenderAssetUsages::RENDER_WORLD,
+    );
+    // Sharp cells once magnified to the tile size.
+    checkerboard.sampler = ImageSampler::nearest();
+    commands.insert_resource(TileStatuses {
+        checkerboard: images.add(checkerboard),
+        pending: HashMap::new(),
+    });
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn update_tile_status(
+    mut commands: Commands,
+    mut indicators: Query<&mut Sprite>,
+    downloads: Query<&TileDownload>,
+    time: Res<Time>,
+    origin: Res<WorldOrigin>,
+    config: Res<MapConfig>,
+    active_layer: Res<ActiveBaseLayer>,
+    offline: Res<OfflineSources>,
+    requested: Res<RequestedTiles>,
+    index: Res<TileIndex>,
+    mut failures: EventReader<TileFailed>,
+    cache: Res<TileCache>,
+    pool: Res<DownloadPool>,
+    mut statuses: ResMut<TileStatuses>,
+) {
+    let now = time.elapsed_seconds();
+    let statuses = &mut *statuses;
+
+    // Tiles that arrived, were unloaded or belong to a previous base layer.
+    statuses.pending.retain(|key, status| {
+        let pending = status.layer == active_layer.index
+            && requested.0.contains(key)
+            && !index.0.contains_key(key);
+        if !pending {
+            commands.entity(status.indicator).despawn();
+        }
+        pending
+    });
+
+    for key in &requested.0 {
+        if index.0.contains_key(key) || statuses.pending.contains_key(key) {
+            continue;
+        }
+        let position = origin.tile_to_world(key.x, key.y, key.zoom_level);
+        let size = tile_world_size(key.zoom_level);
+        let indicator = commands
+            .spawn(SpriteBundle {
+                texture: statuses.checkerboard.clone(),
+                transform: Transform::from_xyz(
+                    position.x,
+                    position.y,
+                    key.zoom_level as f32 + INDICATOR_Z_OFFSET,
+                ),
+                sprite: Sprite {
+                    custom_size: Some(Vec2::new(size, size)),
+                    ..default()
+                },
+                ..default()
+            })
+            .id();
+        statuses.pending.insert(
+            *key,
+            TileStatus {
+                layer: active_layer.index,
+                indicator,
+                attempts: 1,
+                retry_at: None,
+            },
+        );
+    }
+
+    let mut tint = |status: &TileStatus, failed: bool| {
+        if let Ok(mut sprite) = indicators.get_mut(status.indicator) {
+            sprite.color = if failed { ERROR_TINT } else { Color::WHITE };
+        }
+    };
+
+    let archive = offline.0.contains_key(&active_layer.layer(&config).name);
+    for TileFailed(key) in failures.read() {
+        if let Some(status) = statuses.pending.get_mut(key) {
+            tint(status, true);
+            if !archive {
+                schedule_retry(status, now);
+            }
+        }
+    }
+
+    // A failed tile still downloading, like a tile fetched ahead of a flight, is retried once that
+    // download finished without it.
+    let downloading: HashSet<TileKey> = downloads
+        .iter()
+        .filter(|download| download.layer == active_layer.index)
+        .map(|download| download.key)
+        .collect();
+    for (key, status) in &mut statuses.pending {
+        if status.retry_at.is_none_or(|retry_at| now < retry_at) || downloading.contains(key) {
+            continue;
+        }
+        info!(
+            "Retrying slippy tile {}/{}/{}, attempt {}",
+            key.zoom_level,
+            key.x,
+            key.y,
+            status.attempts + 1
+        );
+        commands.spawn(download_tile(&pool, &config, &cache, &active_layer, *key));
+        status.attempts += 1;
+        status.retry_at = None;
+        tint(status, false);
+    }
+}
+
+// Requests the tile again after the backoff delay of its attempts, unless it ran out of attempts.
+fn schedule_retry(status: &mut TileStatus, now: f32) {
+    if status.attempts < MAX_ATTEMPTS {
+        let delay = RETRY_DELAY * 2_f32.powi(status.attempts as i32 - 1);
+        status.retry_at = Some(now + delay.min(MAX_RETRY_DELAY));
+    }
+}
+
+// Downloaded tiles whose image failed to load, an error page or a truncated file, are removed from
+// the map and the cache, and reported as failed to be downloaded again.
+#[allow(clippy::too_many_arguments)]
+pub fn detect_failed_tiles(
+    mut commands: Commands,
+    tiles: Query<(Entity, &MapTile, &Handle<Image>)>,
+    asset_server: Res<AssetServer>,
+    config: Res<MapConfig>,
+    active_layer: Res<ActiveBaseLayer>,
+    offline: Res<OfflineSources>,
+    mut index: ResMut<TileIndex>,
+    mut cache: ResMut<TileCache>,
+    mut failures: EventWriter<TileFailed>,
+) {
+    if offline.0.contains_key(&active_layer.layer(&config).name) {
+        return;
+    }
+    for (entity, MapTile(key), texture) in &tiles {
+        if !matches!(
+            asset_server.get_load_state(texture),
+            Some(LoadState::Failed(_))
+        ) {
+            continue;
+        }
+        warn!(
+            "Slippy tile {}/{}/{} cannot be decoded, downloading it again",
+            key.zoom_level, key.x, key.y
+        );
+        if let Some(path) = texture.path() {
+            cache.remove_tile(path.path());
+        }
+        commands.entity(entity).despawn_recursive();
+        index.0.remove(key);
+        failures.send(TileFailed(*key));
+    }
+}